@@ -1,29 +1,38 @@
 use crate::config::config::apply_config;
+use crate::cue::split::split_cue_images;
+use crate::fs::cleanup;
+use crate::fs::cleanup::CleanupGuard;
 use crate::fs::util::get_all_files_with_extension;
 use crate::redacted::api::client::RedactedApi;
 use crate::redacted::api::constants::{FORBIDDEN_CHARACTERS, TRACKER_URL};
 use crate::redacted::api::path::is_path_exceeding_redacted_path_limit;
-use crate::redacted::models::ReleaseType::{Flac, Flac24, Mp3320, Mp3V0};
+use crate::redacted::models::ReleaseType::{Aac256, Flac, Flac24, Mp3320, Mp3V0, Mp3V2, Opus};
 use crate::redacted::models::{Category, Media, ReleaseType};
 use crate::redacted::upload::TorrentUploadData;
-use crate::redacted::util::{create_description, perma_link};
+use crate::redacted::util::{create_description, perma_link, report_link};
+use crate::scheduler::scheduler::Scheduler;
+use crate::spectrogram::analysis::analyze_release;
 use crate::tags::util::valid_tags;
-use crate::transcode::transcode::transcode_release;
-use crate::transcode::util::copy_other_allowed_files;
-use crate::{imdl, spectrogram, transcode, TranscodeCommand, ERROR, PAUSE, SUCCESS, WARNING};
+use crate::transcode::backend::{EncoderBackend, ProfileBackend};
+use crate::transcode::bit_depth::analyze_bit_depth;
+use crate::transcode::emphasis::detect_pre_emphasis;
+use crate::transcode::integrity::verify_source_integrity;
+use crate::transcode::loudness::{apply_replaygain, supports_replaygain, LoudnessReport};
+use crate::transcode::sample_rate::plan_sample_rate;
+use crate::transcode::transcode::{transcode_release, TranscodeOptions, TranscodeTarget};
+use crate::{imdl, spectrogram, transcode, TranscodeCommand, ERROR, INFO, PAUSE, SUCCESS, WARNING};
 use console::Term;
 use dialoguer::{Confirm, Input};
 use html_escape::decode_html_entities;
-use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use indicatif::{ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env::temp_dir;
 use std::path::PathBuf;
 use std::sync::Arc;
 use strum::IntoEnumIterator;
 use tokio::fs::create_dir_all;
-use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 
 pub async fn transcode(mut cmd: TranscodeCommand, term: &Term) -> anyhow::Result<()> {
@@ -37,22 +46,45 @@ pub async fn transcode(mut cmd: TranscodeCommand, term: &Term) -> anyhow::Result
         SUCCESS, index_response.username
     ))?;
 
-    for url in cmd.urls.clone() {
-        let result = handle_url(
-            url.as_str(),
-            term,
-            &mut api,
-            cmd.clone(),
-            index_response.passkey.clone(),
-        )
-        .await;
+    let scheduler = Arc::new(Scheduler::new(api, cmd.concurrency.unwrap()));
 
-        if let Err(e) = result {
-            term.write_line(&format!(
-                "{} Skipping due to encountered error: {}",
-                ERROR, e
-            ))?;
+    let interrupt_term = term.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = interrupt_term.write_line(&format!(
+                "{} Interrupted, cleaning up temporary files, partial transcodes are kept for --resume...",
+                WARNING
+            ));
+
+            cleanup::cleanup_all();
+
+            std::process::exit(130);
         }
+    });
+    let mut join_set = JoinSet::new();
+
+    for url in cmd.urls.clone() {
+        let term = term.clone();
+        let scheduler = scheduler.clone();
+        let cmd = cmd.clone();
+        let passkey = index_response.passkey.clone();
+
+        join_set.spawn(async move {
+            let result = handle_url(url.as_str(), &term, scheduler, cmd, passkey).await;
+
+            if let Err(e) = result {
+                term.write_line(&format!(
+                    "{} Skipping {} due to encountered error: {}",
+                    ERROR, url, e
+                ))?;
+            }
+
+            Ok::<(), anyhow::Error>(())
+        });
+    }
+
+    while let Some(result) = join_set.join_next().await {
+        result??;
     }
 
     Ok(())
@@ -61,7 +93,7 @@ pub async fn transcode(mut cmd: TranscodeCommand, term: &Term) -> anyhow::Result
 async fn handle_url(
     url: &str,
     term: &Term,
-    api: &mut RedactedApi,
+    scheduler: Arc<Scheduler>,
     mut cmd: TranscodeCommand,
     passkey: String,
 ) -> anyhow::Result<()> {
@@ -72,6 +104,8 @@ async fn handle_url(
         .unwrap();
     }
 
+    let backend = resolve_backend(&cmd);
+
     let captures = match REDACTED_PERMA_LINK_REGEX.captures(url) {
         None => {
             term.write_line(&format!(
@@ -91,7 +125,12 @@ async fn handle_url(
         SUCCESS, torrent_id, group_id
     ))?;
 
-    let group_info = api.get_torrent_group(group_id).await?;
+    let group_info = scheduler
+        .api
+        .lock()
+        .await
+        .get_torrent_group(group_id)
+        .await?;
 
     let group_torrents = group_info.response.torrents;
     let group = group_info.response.group;
@@ -158,6 +197,9 @@ async fn handle_url(
                         "V0 (VBR)" => {
                             existing_formats.insert(Mp3V0);
                         }
+                        "V2 (VBR)" => {
+                            existing_formats.insert(Mp3V2);
+                        }
                         _ => {
                             term.write_line(&format!(
                                 "{} Unknown encoding {} for torrent {} in group {}, this shouldn't happen...",
@@ -166,6 +208,11 @@ async fn handle_url(
                         }
                     }
                 }
+                "AAC" => {
+                    if t.encoding == "256" {
+                        existing_formats.insert(Aac256);
+                    }
+                }
                 _ => {
                     term.write_line(&format!(
                         "{} Unknown format {} for torrent {} in group {}, this shouldn't happen...",
@@ -185,22 +232,41 @@ async fn handle_url(
 
     let mut transcode_formats = Vec::new();
 
+    let source_is_24_bit = torrent.encoding == "24bit Lossless";
+
+    // The source itself is a 24bit FLAC, so only another 24bit torrent in the same edition counts
+    // as an already existing downsampled edition
+    let downsampled_24_bit_exists = group_torrents.iter().any(|t| {
+        t.id != torrent.id
+            && t.remaster_title == torrent.remaster_title
+            && t.remaster_record_label == torrent.remaster_record_label
+            && t.media == torrent.media
+            && t.remaster_catalogue_number == torrent.remaster_catalogue_number
+            && t.format == "FLAC"
+            && t.encoding == "24bit Lossless"
+    });
+
     ReleaseType::iter().for_each(|release_type| {
-        let format_already_exist = existing_formats.contains(&release_type);
-        let release_is_not_flac_24 = release_type != Flac24;
+        let format_already_exist = if release_type == Flac24 {
+            downsampled_24_bit_exists
+        } else {
+            existing_formats.contains(&release_type)
+        };
+        let release_can_be_transcoded =
+            release_type != Flac24 || (cmd.downsample_24bit && source_is_24_bit);
         let release_is_allowed_to_transcode = cmd.allowed_transcode_formats.contains(&release_type);
 
-        let release_is_not_flac_24_and_allowed_to_transcode =
-            release_is_not_flac_24 && release_is_allowed_to_transcode;
+        let release_can_be_and_is_allowed_to_transcode =
+            release_can_be_transcoded && release_is_allowed_to_transcode;
 
         if cmd.skip_existing_formats_check {
-            if release_is_not_flac_24_and_allowed_to_transcode
+            if release_can_be_and_is_allowed_to_transcode
                 && (release_type != Flac || torrent.format != "FLAC")
             {
                 transcode_formats.push(release_type);
             }
         } else {
-            if !format_already_exist && release_is_not_flac_24_and_allowed_to_transcode {
+            if !format_already_exist && release_can_be_and_is_allowed_to_transcode {
                 transcode_formats.push(release_type);
             }
         }
@@ -252,7 +318,96 @@ async fn handle_url(
 
     let content_directory = cmd.content_directory.unwrap();
 
-    let flac_path = content_directory.join(decode_html_entities(&torrent.file_path).to_string());
+    let source_path = content_directory.join(decode_html_entities(&torrent.file_path).to_string());
+
+    let mut description_notes = Vec::new();
+
+    // Corrupt sources are caught before anything gets split or transcoded from them
+    let integrity_report = verify_source_integrity(&source_path, scheduler.semaphore.clone()).await?;
+
+    if !integrity_report.failures.is_empty() {
+        for failure in &integrity_report.failures {
+            term.write_line(&format!("    {} {}", ERROR, failure))?;
+        }
+
+        term.write_line(&format!(
+            "{} Torrent {} in group {} has {} corrupt FLAC file(s), skipping...\n You might be able to trump it.",
+            WARNING, torrent_id, group_id, integrity_report.failures.len()
+        ))?;
+        return Ok(());
+    }
+
+    if integrity_report.tracks_without_md5.is_empty() {
+        term.write_line(&format!(
+            "{} All {} FLAC file(s) of torrent {} in group {} decode cleanly and match their MD5 signature",
+            SUCCESS, integrity_report.checked_tracks, torrent_id, group_id
+        ))?;
+    } else {
+        term.write_line(&format!(
+            "{} {} FLAC file(s) of torrent {} in group {} decode cleanly but have no MD5 signature to compare with",
+            WARNING, integrity_report.tracks_without_md5.len(), torrent_id, group_id
+        ))?;
+    }
+
+    // Images are split into a temporary copy of the release which then stands in for the source,
+    // the torrent hash is still checked against the untouched source
+    let split_directory = temp_dir().join(format!("red_oxide-split-{}", torrent_id));
+
+    // A killed earlier run may have left tracks behind, which must not end up in the upload
+    if tokio::fs::try_exists(&split_directory).await? {
+        tokio::fs::remove_dir_all(&split_directory).await?;
+    }
+
+    let _split_guard = CleanupGuard::new(split_directory.clone());
+    let split_path = split_directory.join(source_path.file_name().unwrap());
+
+    // Profiles only encode WAV and may not have a FLAC template, so the built-in backend splits
+    let split_backend = cmd.encoder_backend.unwrap().backend();
+
+    let flac_path = match split_cue_images(&source_path, &split_path, split_backend, scheduler.semaphore.clone()).await {
+        Ok(None) => source_path.clone(),
+        Ok(Some(split_release)) => {
+            term.write_line(&format!(
+                "{} Split {} image(s) of torrent {} in group {} into {} track(s) along their cue sheets",
+                INFO, split_release.images, torrent_id, group_id, split_release.tracks
+            ))?;
+
+            for skipped_pregap in &split_release.skipped_pregaps {
+                term.write_line(&format!(
+                    "{} The pregap of {} is not part of any track, it was left out",
+                    WARNING, skipped_pregap
+                ))?;
+            }
+
+            description_notes.push(
+                "Split into tracks from a single-file image at the INDEX 01 positions of its cue sheet, pregaps are appended to the previous track.".to_string(),
+            );
+
+            // The split tracks stand in for the source from here on, so they get checked too
+            let split_report = verify_source_integrity(&split_path, scheduler.semaphore.clone()).await?;
+
+            if !split_report.failures.is_empty() {
+                for failure in &split_report.failures {
+                    term.write_line(&format!("    {} {}", ERROR, failure))?;
+                }
+
+                term.write_line(&format!(
+                    "{} {} track(s) split out of torrent {} in group {} do not decode cleanly, skipping",
+                    WARNING, split_report.failures.len(), torrent_id, group_id
+                ))?;
+                return Ok(());
+            }
+
+            split_path
+        }
+        Err(e) => {
+            term.write_line(&format!(
+                "{} Could not split the image(s) of torrent {} in group {}: {}, skipping",
+                WARNING, torrent_id, group_id, e
+            ))?;
+            return Ok(());
+        }
+    };
 
     let media = Media::from(&*torrent.media);
 
@@ -273,15 +428,23 @@ async fn handle_url(
     }
 
     if !cmd.skip_hash_check {
-        let downloaded_torrent = api.download_torrent(torrent.id).await?;
+        let downloaded_torrent = scheduler
+            .api
+            .lock()
+            .await
+            .download_torrent(torrent.id)
+            .await?;
 
         let mut tmp = temp_dir();
         tmp.push(format!("red_oxide-torrent-{}", torrent_id));
 
+        // Removes the temporary .torrent again no matter how the hash check ends
+        let _tmp_guard = CleanupGuard::new(tmp.clone());
+
         tokio::fs::write(&tmp, downloaded_torrent).await?;
 
         let result = imdl::hash::verify_torrent_hash(
-            flac_path.as_path().to_str().unwrap(),
+            source_path.as_path().to_str().unwrap(),
             tmp.to_str().unwrap(),
         )
         .await?;
@@ -298,16 +461,64 @@ async fn handle_url(
             ))?;
             return Ok(());
         }
-
-        tokio::fs::remove_file(&tmp).await?;
     }
 
     let spectrogram_directory = cmd.spectrogram_directory.unwrap();
     let flacs = get_all_files_with_extension(&flac_path, ".flac").await?;
     let flacs_count = flacs.len();
 
+    let mut looks_lossless = true;
+
+    if !cmd.skip_spectrogram || cmd.reject_lossy {
+        term.write_line(&format!(
+            "{} Analyzing spectra of torrent {} in group {}...",
+            INFO, torrent_id, group_id
+        ))?;
+
+        let report = match analyze_release(&flac_path, scheduler.semaphore.clone()).await {
+            Ok(report) => report,
+            Err(e) => {
+                term.write_line(&format!(
+                    "{} Could not analyze the spectra of torrent {} in group {}: {}, skipping",
+                    WARNING, torrent_id, group_id, e
+                ))?;
+                return Ok(());
+            }
+        };
+
+        let suspicious_tracks = report.suspicious_tracks();
+
+        term.write_line(&format!(
+            "{} Spectral analysis: {} of {} track(s) look lossless",
+            if suspicious_tracks.is_empty() { SUCCESS } else { WARNING },
+            report.tracks.len() - suspicious_tracks.len(),
+            report.tracks.len()
+        ))?;
+
+        for track in &suspicious_tracks {
+            term.write_line(&format!(
+                "    {} {}: {}",
+                WARNING,
+                track.path.strip_prefix(&flac_path)?.display(),
+                track
+            ))?;
+        }
+
+        if cmd.reject_lossy && report.is_obviously_lossy() {
+            term.write_line(&format!(
+                "{} Torrent {} in group {} looks like a lossy master, skipping",
+                ERROR, torrent_id, group_id
+            ))?;
+            return Ok(());
+        }
+
+        looks_lossless = suspicious_tracks.is_empty();
+    }
+
     if !cmd.skip_spectrogram {
-        let pb = ProgressBar::new(flacs_count as u64);
+        let pb = scheduler
+            .multi_progress
+            .add(ProgressBar::new(flacs_count as u64));
 
         pb.set_style(
             ProgressStyle::with_template(
@@ -323,7 +534,7 @@ async fn handle_url(
 
         create_dir_all(&to_create).await?;
 
-        let semaphore = Arc::new(Semaphore::new(cmd.concurrency.unwrap()));
+        let semaphore = scheduler.semaphore.clone();
         let mut tasks = vec![];
 
         for flac in flacs {
@@ -384,17 +595,26 @@ async fn handle_url(
             task.await??;
         }
 
-        let mut prompt = Confirm::new();
-
         pb.finish_and_clear();
+        scheduler.multi_progress.remove(&pb);
+
+        let gallery_path = spectrogram::gallery::write_gallery(&flac_path, &to_create).await?;
 
-        term.write_line(&*format!("{} Created Spectrograms at {}, please manual check if FLAC is lossless before continuing!", PAUSE, to_create.to_str().unwrap()))?;
+        let prompt_term = term.clone();
 
-        prompt = prompt
-            .with_prompt("Do those spectrograms look good?")
-            .default(true);
+        let response = scheduler
+            .prompt(move || {
+                let mut prompt = Confirm::new();
 
-        let response = prompt.interact()?;
+                prompt_term.write_line(&format!("{} Created Spectrograms at {}, please manual check if FLAC is lossless before continuing!", PAUSE, gallery_path.to_str().unwrap()))?;
+
+                prompt = prompt
+                    .with_prompt("Do those spectrograms look good?")
+                    .default(looks_lossless);
+
+                Ok(prompt.interact()?)
+            })
+            .await?;
 
         if !response {
             term.write_line(&format!(
@@ -406,14 +626,168 @@ async fn handle_url(
     }
 
     if transcode::util::is_multichannel(&flac_path).await? {
+        if !cmd.downmix {
+            term.write_line(&format!(
+                "{} Torrent {} in group {} is a multichannel release which is unsupported without downmix, skipping",
+                WARNING, torrent_id, group_id
+            ))?;
+            return Ok(());
+        }
+
         term.write_line(&format!(
-            "{} Torrent {} in group {} is a multichannel release which is unsupported, skipping",
-            WARNING, torrent_id, group_id
+            "{} Torrent {} in group {} is a multichannel release, it will be downmixed to stereo",
+            INFO, torrent_id, group_id
         ))?;
-        return Ok(());
+
+        description_notes.push(
+            "Downmixed to stereo from a multichannel source with the ITU-R BS.775 coefficients (center and surround channels at -3dB, LFE dropped), scaled down to prevent clipping.".to_string(),
+        );
     }
 
-    let multi_progress = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    let mut padded_source = false;
+
+    if transcode::util::is_24_bit_flac(&flac_path).await? {
+        let bit_depth_report = match analyze_bit_depth(&flac_path, scheduler.semaphore.clone()).await {
+            Ok(bit_depth_report) => bit_depth_report,
+            Err(e) => {
+                term.write_line(&format!(
+                    "{} Could not check the bit depth of torrent {} in group {}: {}, skipping",
+                    WARNING, torrent_id, group_id, e
+                ))?;
+                return Ok(());
+            }
+        };
+
+        if bit_depth_report.is_padded() {
+            padded_source = true;
+
+            term.write_line(&format!(
+                "{} Torrent {} in group {} is no real hi-res release, it is a {}",
+                WARNING, torrent_id, group_id, bit_depth_report
+            ))?;
+
+            let report_text = format!(
+                "The source is a 16bit recording padded to 24bit and can be trumped by a 16bit FLAC, every track was decoded and the {}",
+                bit_depth_report
+            );
+
+            // Nobody is there to answer in runs without a terminal, they only get the report text
+            let report = if !term.is_term() {
+                true
+            } else {
+                scheduler
+                    .prompt(|| {
+                        Ok(Confirm::new()
+                            .with_prompt("Do you want to report the source as a trump candidate?")
+                            .default(true)
+                            .interact()?)
+                    })
+                    .await?
+            };
+
+            if report {
+                term.write_line(&format!(
+                    "{} Report it at {} as a trump candidate with:\n{}",
+                    INFO,
+                    report_link(torrent_id),
+                    report_text
+                ))?;
+            }
+
+            description_notes.push(format!(
+                "The 24bit source only uses {} bits (zero-padded), tracks needing no other processing were reduced to 16bit by dropping the padding without dither, which keeps all of their audio.",
+                bit_depth_report.used_bits().unwrap_or(16)
+            ));
+
+            if transcode_formats.contains(&Flac24) {
+                term.write_line(&format!(
+                    "{} Torrent {} in group {} has no real 24bit audio, no downsampled {} will be made",
+                    INFO, torrent_id, group_id, Flac24
+                ))?;
+
+                transcode_formats.retain(|format| *format != Flac24);
+
+                if transcode_formats.is_empty() {
+                    term.write_line(&format!(
+                        "{} Torrent {} in group {} has all possible/wanted formats already... skipping",
+                        WARNING, torrent_id, group_id
+                    ))?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    let sample_rate_plan = match plan_sample_rate(&flac_path).await {
+        Ok(sample_rate_plan) => sample_rate_plan,
+        Err(e) => {
+            term.write_line(&format!(
+                "{} Torrent {} in group {} can not be resampled: {}, skipping",
+                WARNING, torrent_id, group_id, e
+            ))?;
+            return Ok(());
+        }
+    };
+
+    term.write_line(&format!(
+        "{} Sample rate plan for torrent {} in group {}: {}",
+        INFO, torrent_id, group_id, sample_rate_plan
+    ))?;
+
+    let emphasized_tracks = match detect_pre_emphasis(&flac_path).await {
+        Ok(emphasized_tracks) => emphasized_tracks,
+        Err(e) => {
+            term.write_line(&format!(
+                "{} Could not check torrent {} in group {} for pre-emphasis: {}, skipping",
+                WARNING, torrent_id, group_id, e
+            ))?;
+            return Ok(());
+        }
+    };
+
+    if !emphasized_tracks.is_empty() {
+        let sources = emphasized_tracks
+            .values()
+            .cloned()
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        term.write_line(&format!(
+            "{} Found pre-emphasis on {} track(s) of torrent {} in group {} ({}), they will be de-emphasized",
+            INFO,
+            emphasized_tracks.len(),
+            torrent_id,
+            group_id,
+            sources
+        ))?;
+
+        description_notes.push(format!(
+            "De-emphasis (50/15µs) was applied to {} track(s) flagged with pre-emphasis ({}).",
+            emphasized_tracks.len(),
+            sources
+        ));
+    }
+
+    if transcode_formats.contains(&Flac24) && !sample_rate_plan.needs_downsampling() {
+        term.write_line(&format!(
+            "{} Torrent {} in group {} is not above 48kHz, no downsampled {} needed",
+            INFO, torrent_id, group_id, Flac24
+        ))?;
+
+        transcode_formats.retain(|format| *format != Flac24);
+
+        if transcode_formats.is_empty() {
+            term.write_line(&format!(
+                "{} Torrent {} in group {} has all possible/wanted formats already... skipping",
+                WARNING, torrent_id, group_id
+            ))?;
+            return Ok(());
+        }
+    }
+
+    let multi_progress = &scheduler.multi_progress;
     let sty = ProgressStyle::with_template(
         "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
     )
@@ -428,23 +802,28 @@ async fn handle_url(
 
     pb_main.tick();
 
-    let semaphore = Arc::new(Semaphore::new(cmd.concurrency.unwrap()));
-    let mut join_set = JoinSet::new();
+    let mut release_pbs = vec![pb_main.clone()];
 
     multi_progress.println("[➡️] Transcoding...").unwrap();
 
     let transcode_directory = cmd.transcode_directory.unwrap();
 
+    let mut transcode_targets = Vec::new();
+
     for format in &transcode_formats {
         let pb_format =
             multi_progress.insert_before(&pb_main, ProgressBar::new(flacs_count as u64));
         pb_format.set_style(sty.clone());
+        release_pbs.push(pb_format.clone());
 
         let transcode_format_str = match format {
             Flac24 => "FLAC 24bit",
             Flac => "FLAC",
             Mp3320 => "MP3 - 320",
             Mp3V0 => "MP3 - V0",
+            Mp3V2 => "MP3 - V2",
+            Aac256 => "AAC - 256",
+            Opus => "Opus",
         };
 
         let transcode_release_name = format!(
@@ -454,200 +833,346 @@ async fn handle_url(
             transcode_format_str
         );
 
-        let flac_path_clone = flac_path.clone();
-        let torrent_id_clone = torrent_id.clone();
-        let term = Arc::new(term.clone());
-        let mut output_dir = transcode_directory.clone();
-        let format = format.clone();
-        let pb_main_clone = pb_main.clone();
-        let semaphore_clone = semaphore.clone();
-        join_set.spawn(tokio::spawn(async move {
-            let (folder_path, command) = transcode_release(
-                &flac_path_clone,
-                &mut output_dir,
-                transcode_release_name.clone(),
-                format,
-                term,
-                torrent_id_clone,
-                pb_format,
-                pb_main_clone,
-                semaphore_clone,
-            )
-            .await?;
+        transcode_targets.push(TranscodeTarget {
+            format: *format,
+            folder_name: transcode_release_name,
+            pb_format,
+        });
+    }
 
-            let transcode_folder_path = output_dir.join(&folder_path);
+    let transcode_options = TranscodeOptions {
+        torrent_id,
+        resume: cmd.resume,
+        external_decoder: cmd.external_decoder,
+        downmix: cmd.downmix,
+        sample_rate_plan,
+        emphasized_tracks: emphasized_tracks.into_keys().collect(),
+        padded_source,
+        backend,
+    };
 
-            copy_other_allowed_files(&flac_path_clone, &flac_path_clone, &transcode_folder_path)
-                .await?;
+    let transcode_results = transcode_release(
+        &flac_path,
+        &transcode_directory,
+        transcode_targets,
+        Arc::new(transcode_options),
+        Arc::new(term.clone()),
+        pb_main.clone(),
+        scheduler.semaphore.clone(),
+    )
+    .await?;
 
-            return Ok::<(PathBuf, ReleaseType, String), anyhow::Error>((
-                folder_path,
-                format,
-                command,
-            ));
-        }));
+    multi_progress.println(format!("{} Transcoding Done!", SUCCESS))?;
+
+    for pb in &release_pbs {
+        multi_progress.remove(pb);
     }
 
+    let mut summary = Vec::new();
     let mut path_format_command_triple = Vec::new();
+    let mut loudness_reports = HashMap::new();
+
+    for (format, result) in transcode_results {
+        let release = match result {
+            Ok(release) => release,
+            Err(e) => {
+                summary.push((format, Err(e)));
+                continue;
+            }
+        };
 
-    while let Some(res) = join_set.join_next().await {
-        let transcode_folder = res???;
+        if cmd.replaygain && supports_replaygain(&format) {
+            // The transcode itself is verified, so it is kept when tagging fails. It may have
+            // the tags of only some tracks though, so it is not uploaded.
+            match apply_replaygain(&release.path, scheduler.semaphore.clone()).await {
+                Ok(report) => {
+                    term.write_line(&format!(
+                        "{} Wrote ReplayGain tags for format {}, {}",
+                        SUCCESS, format, report
+                    ))?;
+                    loudness_reports.insert(format, report);
+                }
+                Err(e) => {
+                    summary.push((
+                        format,
+                        Err(e.context(format!(
+                            "Could not write ReplayGain tags, not uploaded but kept at {}",
+                            release.path.to_str().unwrap()
+                        ))),
+                    ));
+                    continue;
+                }
+            }
+        }
 
-        path_format_command_triple.push(transcode_folder);
+        path_format_command_triple.push((
+            release.path,
+            format,
+            release.command,
+            release.encoder_settings,
+        ));
     }
 
-    multi_progress.println(format!("{} Transcoding Done!", SUCCESS))?;
-    multi_progress.clear()?;
-
     if invalid_track_number_vinyl {
-        let mut prompt = Confirm::new();
+        scheduler
+            .prompt(|| {
+                let mut prompt = Confirm::new();
 
-        prompt = prompt
-            .with_prompt(format!("{} Please check tags of trancoded media and adjust as needed (release is vinyl and has either no track number or in an non standard format e.g. A1, A2 etc which the audiotags library used can't parse), continue?", WARNING))
-            .default(true);
+                prompt = prompt
+                    .with_prompt(format!("{} Please check tags of trancoded media and adjust as needed (release is vinyl and has either no track number or in an non standard format e.g. A1, A2 etc which the audiotags library used can't parse), continue?", WARNING))
+                    .default(true);
 
-        prompt.interact()?;
+                Ok(prompt.interact()?)
+            })
+            .await?;
     }
 
     let torrent_directory = cmd.torrent_directory.unwrap();
+    let mut local_only = Vec::new();
+
+    for (path, format, command, encoder_settings) in &path_format_command_triple {
+        let Some(format_red) = red_format(format) else {
+            // Only meant for personal libraries or other trackers, a .torrent for RED would be
+            // of no use
+            local_only.push((*format, path.clone()));
+            continue;
+        };
 
-    for (path, format, command) in &path_format_command_triple {
-        let release_name = path.file_name().unwrap().to_str().unwrap();
-        let mut exceeds_red_path_length = is_path_exceeding_redacted_path_limit(&path).await?;
+        // Every format is uploaded on its own, a failing one does not stop the others
+        let result = async {
+            let release_name = path.file_name().unwrap().to_str().unwrap();
+            let mut exceeds_red_path_length = is_path_exceeding_redacted_path_limit(&path).await?;
+
+            while exceeds_red_path_length {
+                let release_name = release_name.to_string();
+
+                let edited_text: String = scheduler
+                    .prompt(move || {
+                        let editor = Input::new();
+
+                        Ok(editor
+                            .with_prompt(format!(
+                                "{} Folder Name {} is too long for RED, please shorten the folder name\n",
+                                ERROR, release_name
+                            ))
+                            .default(release_name.to_string())
+                            .interact_text()?)
+                    })
+                    .await?;
 
-        while exceeds_red_path_length {
-            let editor = Input::new();
+                let new_path = path.parent().unwrap().join(edited_text);
+                exceeds_red_path_length = is_path_exceeding_redacted_path_limit(&new_path).await?;
+            }
 
-            let edited_text = editor
-                .with_prompt(format!(
-                    "{} Folder Name {} is too long for RED, please shorten the folder name\n",
-                    ERROR, release_name
-                ))
-                .default(release_name.to_string())
-                .interact_text()?;
+            let torrent_path = torrent_directory.join(release_name.to_owned() + ".torrent");
 
-            let new_path = path.parent().unwrap().join(edited_text);
-            exceeds_red_path_length = is_path_exceeding_redacted_path_limit(&new_path).await?;
-        }
+            imdl::torrent::create_torrent(
+                path,
+                &torrent_path,
+                format!("{}/{}/announce", TRACKER_URL, passkey),
+            )
+            .await?;
 
-        let torrent_path = torrent_directory.join(release_name.to_owned() + ".torrent");
+            term.write_line(&format!(
+                "{} Created .torrent files for format {}",
+                SUCCESS, format
+            ))?;
 
-        imdl::torrent::create_torrent(
-            path,
-            &torrent_path,
-            format!("{}/{}/announce", TRACKER_URL, passkey),
-        )
-        .await?;
+            let torrent_file_data = tokio::fs::read(&torrent_path).await?;
+
+            let perma_link = perma_link(group_id, torrent_id);
+            let description = create_description(
+                perma_link.clone(),
+                command.clone(),
+                encoder_settings.clone(),
+                &description_notes,
+            );
+
+            let bitrate = match format {
+                Flac24 => "24bit Lossless".to_string(),
+                Flac => "Lossless".to_string(),
+                Mp3320 => "320".to_string(),
+                Mp3V0 => "V0 (VBR)".to_string(),
+                Mp3V2 => "V2 (VBR)".to_string(),
+                Aac256 => "256".to_string(),
+                Opus => "Other".to_string(),
+            };
 
-        term.write_line(&format!(
-            "{} Created .torrent files for format {}",
-            SUCCESS, format
-        ))?;
+            if cmd.move_transcode_to_content {
+                tokio::fs::rename(&path, &content_directory.join(path.file_name().unwrap())).await?;
 
-        let torrent_file_data = tokio::fs::read(&torrent_path).await?;
+                term.write_line(&format!(
+                    "{} Moved transcode release to content directory",
+                    SUCCESS,
+                ))?;
+            }
 
-        let perma_link = perma_link(group_id, torrent_id);
-        let description = create_description(perma_link.clone(), command.clone());
+            if !cmd.automatic_upload {
+                term.write_line(&*format!(
+                    "{} Manual mode enabled, skipping automatic upload",
+                    PAUSE
+                ))?;
+
+                let scene = if torrent.scene { "Yes" } else { "No" };
+                let format = format_red;
+
+                // Collected up front so the details are printed right next to the queued prompt
+                let lines = vec![
+                    "Link: ".to_owned() + &*perma_link,
+                    "Name: ".to_owned() + &*group.name.clone(),
+                    "Artist(s): ".to_owned()
+                        + &group
+                            .music_info
+                            .artists
+                            .iter()
+                            .map(|a| a.name.clone())
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                    "Edition Year: ".to_owned() + &*torrent.remaster_year.to_string(),
+                    "Edition Title: ".to_owned() + &torrent.remaster_title,
+                    "Record Label: ".to_owned() + &torrent.remaster_record_label,
+                    "Catalogue Number: ".to_owned() + &torrent.remaster_catalogue_number,
+                    "Scene: ".to_owned() + scene,
+                    "Format: ".to_owned() + format,
+                    "Bitrate: ".to_owned() + &bitrate,
+                    "Media: ".to_owned() + &torrent.media,
+                    "Release Description:".to_owned(),
+                    description.clone(),
+                ];
+
+                let prompt_term = term.clone();
+
+                scheduler
+                    .prompt(move || {
+                        for line in lines {
+                            prompt_term.write_line(&line)?;
+                        }
 
-        let format_red = match format {
-            Flac24 => "FLAC",
-            Flac => "FLAC",
-            Mp3320 => "MP3",
-            Mp3V0 => "MP3",
-        };
+                        let mut prompt = Confirm::new();
 
-        let bitrate = match format {
-            Flac24 => "24bit Lossless".to_string(),
-            Flac => "Lossless".to_string(),
-            Mp3320 => "320".to_string(),
-            Mp3V0 => "V0 (VBR)".to_string(),
-        };
+                        prompt = prompt
+                            .with_prompt("Confirm once you are done uploading...")
+                            .default(true);
 
-        if cmd.move_transcode_to_content {
-            tokio::fs::rename(&path, &content_directory.join(path.file_name().unwrap())).await?;
+                        Ok(prompt.interact()?)
+                    })
+                    .await?;
+            } else if !cmd.dry_run {
+                let year = if torrent.remaster_year == 0 {
+                    group.year
+                } else {
+                    torrent.remaster_year
+                };
+
+                let upload_data = TorrentUploadData {
+                    torrent: torrent_file_data,
+                    torrent_name: torrent_path
+                        .file_name()
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                    r#type: Category::from(&*group.category_name),
+                    remaster_year: year,
+                    remaster_title: torrent.remaster_title.clone(),
+                    remaster_record_label: torrent.remaster_record_label.clone(),
+                    remaster_catalogue_number: torrent.remaster_catalogue_number.clone(),
+                    format: format_red.to_string(),
+                    bitrate: bitrate.clone(),
+                    media: torrent.media.clone(),
+                    release_desc: description.clone(),
+                    group_id: group.id as u64,
+                };
+
+                let res = scheduler
+                    .api
+                    .lock()
+                    .await
+                    .upload_torrent(upload_data)
+                    .await?;
 
-            term.write_line(&format!(
-                "{} Moved transcode release to content directory",
-                SUCCESS,
-            ))?;
+                term.write_line(&format!("[🔼] Uploaded {} release to REDacted https://redacted.sh/torrents.php?id={}&torrentid={}", format, group_id, res.response.torrent_id))?;
+            }
+
+            Ok::<(), anyhow::Error>(())
         }
+        .await;
 
-        if !cmd.automatic_upload {
-            term.write_line(&*format!(
-                "{} Manual mode enabled, skipping automatic upload",
-                PAUSE
-            ))?;
+        summary.push((*format, result));
+    }
 
-            let scene = if torrent.scene { "Yes" } else { "No" };
-            let format = match format {
-                Flac24 => "FLAC",
-                Flac => "FLAC",
-                Mp3320 => "MP3",
-                Mp3V0 => "MP3",
-            };
+    print_release_summary(
+        term,
+        torrent_id,
+        group_id,
+        summary,
+        &local_only,
+        &loudness_reports,
+    )?;
 
-            term.write_line(&*("Link: ".to_owned() + &*perma_link))?;
-            term.write_line(&*("Name: ".to_owned() + &*group.name.clone()))?;
-            term.write_line(
-                &*("Artist(s): ".to_owned()
-                    + &group
-                        .music_info
-                        .artists
-                        .iter()
-                        .map(|a| a.name.clone())
-                        .collect::<Vec<String>>()
-                        .join(", ")),
-            )?;
-            term.write_line(&*("Edition Year: ".to_owned() + &*torrent.remaster_year.to_string()))?;
-            term.write_line(&*("Edition Title: ".to_owned() + &torrent.remaster_title))?;
-            term.write_line(&*("Record Label: ".to_owned() + &torrent.remaster_record_label))?;
-            term.write_line(
-                &*("Catalogue Number: ".to_owned() + &torrent.remaster_catalogue_number),
-            )?;
-            term.write_line(&*("Scene: ".to_owned() + scene))?;
-            term.write_line(&*("Format: ".to_owned() + format))?;
-            term.write_line(&*("Bitrate: ".to_owned() + &bitrate))?;
-            term.write_line(&*("Media: ".to_owned() + &torrent.media))?;
-            term.write_line("Release Description:")?;
-            term.write_line(&description)?;
-
-            let mut prompt = Confirm::new();
-
-            prompt = prompt
-                .with_prompt("Confirm once you are done uploading...")
-                .default(true);
-
-            prompt.interact()?;
-        } else if !cmd.dry_run {
-            let year = if torrent.remaster_year == 0 {
-                group.year
-            } else {
-                torrent.remaster_year
-            };
+    Ok(())
+}
 
-            let upload_data = TorrentUploadData {
-                torrent: torrent_file_data,
-                torrent_name: torrent_path
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-                r#type: Category::from(&*group.category_name),
-                remaster_year: year,
-                remaster_title: torrent.remaster_title.clone(),
-                remaster_record_label: torrent.remaster_record_label.clone(),
-                remaster_catalogue_number: torrent.remaster_catalogue_number.clone(),
-                format: format_red.to_string(),
-                bitrate: bitrate.clone(),
-                media: torrent.media.clone(),
-                release_desc: description.clone(),
-                group_id: group.id as u64,
-            };
+/// A configured encoder profile takes precedence over the encoder backend.
+fn resolve_backend(cmd: &TranscodeCommand) -> Arc<dyn EncoderBackend> {
+    match &cmd.encoder_profile {
+        Some(encoder_profile) => Arc::new(ProfileBackend {
+            profile: cmd.encoder_profiles[encoder_profile].clone(),
+        }),
+        None => cmd.encoder_backend.unwrap().backend(),
+    }
+}
 
-            let res = api.upload_torrent(upload_data).await?;
+/// The format name RED uses for a release type, `None` if RED does not allow the format at all.
+fn red_format(format: &ReleaseType) -> Option<&'static str> {
+    match format {
+        Flac24 | Flac => Some("FLAC"),
+        Mp3320 | Mp3V0 | Mp3V2 => Some("MP3"),
+        Aac256 => Some("AAC"),
+        Opus => None,
+    }
+}
 
-            term.write_line(&format!("[🔼] Uploaded {} release to REDacted https://redacted.sh/torrents.php?id={}&torrentid={}", format, group_id, res.response.torrent_id))?;
+/// Uploaded formats are listed with their result, formats RED does not allow were only
+/// transcoded and are listed with where they were kept.
+fn print_release_summary(
+    term: &Term,
+    torrent_id: i64,
+    group_id: i64,
+    summary: Vec<(ReleaseType, anyhow::Result<()>)>,
+    local_only: &[(ReleaseType, PathBuf)],
+    loudness_reports: &HashMap<ReleaseType, LoudnessReport>,
+) -> anyhow::Result<()> {
+    term.write_line(&format!(
+        "{} Summary for torrent {} in group {}:",
+        INFO, torrent_id, group_id
+    ))?;
+
+    for (format, result) in summary {
+        match result {
+            Ok(()) => match loudness_reports.get(&format) {
+                Some(report) => {
+                    term.write_line(&format!("    {} {} ({})", SUCCESS, format, report))?
+                }
+                None => term.write_line(&format!("    {} {}", SUCCESS, format))?,
+            },
+            Err(e) => term.write_line(&format!("    {} {}: {}", ERROR, format, e))?,
+        }
+    }
+
+    if !local_only.is_empty() {
+        term.write_line(&format!(
+            "{} Transcoded only, RED does not allow these formats so no .torrent was created:",
+            INFO
+        ))?;
+
+        for (format, path) in local_only {
+            term.write_line(&format!(
+                "    {} {} at {}",
+                WARNING,
+                format,
+                path.to_str().unwrap()
+            ))?;
         }
     }
 
@@ -2,7 +2,8 @@ use crate::config::constants::{
     CONFIG_FILE_NAME, CONFIG_PATH, HOME_ENV, PROJECT_NAME, WINDOWS_APPDATA_ENV, XDG_CONFIG_ENV,
 };
 use crate::config::models::RedOxideConfig;
-use crate::redacted::models::ReleaseType::{Flac, Mp3320, Mp3V0};
+use crate::redacted::models::ReleaseType::{Flac, Flac24, Mp3320, Mp3V0};
+use crate::transcode::backend::{validate_profile, EncoderBackendType};
 use crate::{TranscodeCommand, ERROR};
 use console::Term;
 use std::env;
@@ -163,6 +164,38 @@ pub async fn apply_config(cmd: &mut TranscodeCommand, term: &Term) -> anyhow::Re
         if let Some(concurrency) = &config.concurrency {
             cmd.concurrency = Some(*concurrency);
         }
+
+        if let Some(external_decoder) = &config.external_decoder {
+            cmd.external_decoder = *external_decoder;
+        }
+
+        if let Some(downsample_24bit) = &config.downsample_24bit {
+            cmd.downsample_24bit = *downsample_24bit;
+        }
+
+        if let Some(downmix) = &config.downmix {
+            cmd.downmix = *downmix;
+        }
+
+        if let Some(replaygain) = &config.replaygain {
+            cmd.replaygain = *replaygain;
+        }
+
+        if let Some(reject_lossy) = &config.reject_lossy {
+            cmd.reject_lossy = *reject_lossy;
+        }
+
+        if cmd.encoder_backend.is_none() {
+            cmd.encoder_backend = config.encoder_backend;
+        }
+
+        if cmd.encoder_profile.is_none() {
+            cmd.encoder_profile = config.encoder_profile;
+        }
+
+        if let Some(encoder_profiles) = config.encoder_profiles {
+            cmd.encoder_profiles = encoder_profiles;
+        }
     }
 
     verify_final_config(cmd, term)?;
@@ -215,9 +248,46 @@ pub fn verify_final_config(cmd: &mut TranscodeCommand, term: &Term) -> anyhow::R
         cmd.allowed_transcode_formats = vec![Flac, Mp3320, Mp3V0];
     }
 
+    if cmd.downsample_24bit && !cmd.allowed_transcode_formats.contains(&Flac24) {
+        cmd.allowed_transcode_formats.push(Flac24);
+    }
+
     if cmd.concurrency.is_none() {
         cmd.concurrency = Some(num_cpus::get());
     }
 
+    if cmd.encoder_backend.is_none() {
+        cmd.encoder_backend = Some(EncoderBackendType::Tools);
+    }
+
+    if let Some(encoder_profile) = &cmd.encoder_profile {
+        let profile = match cmd.encoder_profiles.get(encoder_profile) {
+            None => {
+                term.write_line(&format!(
+                    "{} Encoder profile {} is not defined in the config file",
+                    ERROR, encoder_profile
+                ))?;
+                std::process::exit(1);
+            }
+            Some(profile) => profile,
+        };
+
+        if cmd.downmix {
+            term.write_line(&format!(
+                "{} Encoder profile {} can not be combined with downmix, profiles can not downmix",
+                ERROR, encoder_profile
+            ))?;
+            std::process::exit(1);
+        }
+
+        if let Err(reason) = validate_profile(profile, &cmd.allowed_transcode_formats) {
+            term.write_line(&format!(
+                "{} Encoder profile {} is invalid: {}",
+                ERROR, encoder_profile, reason
+            ))?;
+            std::process::exit(1);
+        }
+    }
+
     Ok(())
 }
@@ -1,6 +1,8 @@
 use crate::redacted::models::ReleaseType;
+use crate::transcode::backend::EncoderBackendType;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -16,4 +18,30 @@ pub struct RedOxideConfig {
     pub skip_spectrogram: Option<bool>,
     pub allowed_transcode_formats: Option<Vec<ReleaseType>>,
     pub concurrency: Option<usize>,
+    pub external_decoder: Option<bool>,
+    pub downsample_24bit: Option<bool>,
+    pub downmix: Option<bool>,
+    pub replaygain: Option<bool>,
+    pub reject_lossy: Option<bool>,
+    pub encoder_backend: Option<EncoderBackendType>,
+    pub encoder_profile: Option<String>,
+    pub encoder_profiles: Option<HashMap<String, EncoderProfile>>,
+}
+
+/// Command templates replacing the built-in encoder commands. `{input}`, `{output}` and `{rate}`
+/// are replaced with the source file, the output file and the target sample rate.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncoderProfile {
+    /// Decodes a FLAC which needs no resampling to WAV on stdout
+    pub decoder: String,
+    /// Resamples a FLAC to `{rate}` and 16 bit WAV on stdout, `{dither}` is replaced with the
+    /// dither arguments
+    pub resampler: String,
+    /// Resamples a FLAC to `{rate}` and 24 bit WAV on stdout, only needed for downsampled 24 bit
+    /// FLACs
+    pub resampler_24bit: Option<String>,
+    /// The dither arguments of the resampler, defaults to `dither`, empty disables dithering
+    pub dither: Option<String>,
+    /// Encodes WAV from stdin to the format
+    pub encoders: HashMap<ReleaseType, String>,
 }
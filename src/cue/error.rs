@@ -0,0 +1,27 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CueError {
+    #[error("Cue sheet \"{0}\" has an invalid line {1}: {2}")]
+    InvalidLine(PathBuf, usize, String),
+
+    #[error("Cue sheet \"{0}\" has a TRACK before any FILE in line {1}")]
+    TrackWithoutFile(PathBuf, usize),
+
+    #[error("Cue sheet \"{0}\" has an invalid timestamp {2} in line {1}")]
+    InvalidTimestamp(PathBuf, usize, String),
+
+    #[error("Cue sheet \"{0}\" has no INDEX 01 for track {1}")]
+    MissingTrackStart(PathBuf, u32),
+
+    #[error("Cue sheet \"{0}\" places track {1} outside of the image \"{2}\"")]
+    TrackOutsideOfImage(PathBuf, u32, PathBuf),
+
+    #[error("Failed to split track {1} out of \"{0}\": {2}")]
+    SplitFailed(PathBuf, u32, String),
+
+    #[error("Image \"{0}\" has {1} bits per sample, only up to 24 can be split")]
+    UnsupportedBitDepth(PathBuf, u32),
+}
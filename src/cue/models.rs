@@ -0,0 +1,53 @@
+/// Cue sheet timestamps count CD frames, 75 of them per second.
+pub const FRAMES_PER_SECOND: u64 = 75;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueSheet {
+    pub performer: Option<String>,
+    pub title: Option<String>,
+    /// `REM` lines like `REM DATE 1999` as key and value
+    pub remarks: Vec<(String, String)>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueFile {
+    pub name: String,
+    pub file_type: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub track_type: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub isrc: Option<String>,
+    pub flags: Vec<String>,
+    pub indexes: Vec<CueIndex>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CueIndex {
+    pub number: u32,
+    /// Position in the file in CD frames
+    pub frames: u64,
+}
+
+impl CueTrack {
+    /// Where the track starts in CD frames, its `INDEX 01`.
+    pub fn start(&self) -> Option<u64> {
+        self.indexes
+            .iter()
+            .find(|index| index.number == 1)
+            .map(|index| index.frames)
+    }
+
+    /// If the track was mastered with pre-emphasis, which is marked by the `PRE` flag.
+    pub fn has_pre_emphasis(&self) -> bool {
+        self.flags
+            .iter()
+            .any(|flag| flag.eq_ignore_ascii_case("PRE"))
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::Path;
+
+use crate::cue::error::CueError::{InvalidLine, InvalidTimestamp, TrackWithoutFile};
+use crate::cue::models::{CueFile, CueIndex, CueSheet, CueTrack, FRAMES_PER_SECOND};
+use crate::fs::util::decode_text;
+
+/// Reads and parses a cue sheet, whatever text encoding the ripper saved it in.
+pub async fn read_cue_sheet(path: &Path) -> anyhow::Result<CueSheet> {
+    let content = decode_text(&tokio::fs::read(path).await?);
+
+    parse_cue_sheet(path, &content)
+}
+
+pub fn parse_cue_sheet(path: &Path, content: &str) -> anyhow::Result<CueSheet> {
+    let mut sheet = CueSheet::default();
+
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let tokens = tokenize(line);
+
+        let Some(command) = tokens.first() else {
+            continue;
+        };
+
+        let invalid = || InvalidLine(path.to_path_buf(), line_number, line.trim().to_string());
+        let argument = |position: usize| tokens.get(position).cloned().ok_or_else(invalid);
+
+        match command.to_uppercase().as_str() {
+            // Remarks inside FILE or TRACK blocks (e.g. per-track gain) are not needed
+            "REM" if tokens.len() > 2 && sheet.files.is_empty() => {
+                sheet
+                    .remarks
+                    .push((tokens[1].to_uppercase(), tokens[2..].join(" ")));
+            }
+            "FILE" => {
+                // The file type is the last token, the name may have been written without quotes
+                if tokens.len() < 3 {
+                    return Err(invalid().into());
+                }
+
+                sheet.files.push(CueFile {
+                    name: tokens[1..tokens.len() - 1].join(" "),
+                    file_type: tokens[tokens.len() - 1].to_uppercase(),
+                    tracks: vec![],
+                });
+            }
+            "TRACK" => {
+                let number = argument(1)?.parse().map_err(|_| invalid())?;
+                let track_type = argument(2)?.to_uppercase();
+
+                let file = sheet
+                    .files
+                    .last_mut()
+                    .ok_or_else(|| TrackWithoutFile(path.to_path_buf(), line_number))?;
+
+                file.tracks.push(CueTrack {
+                    number,
+                    track_type,
+                    ..CueTrack::default()
+                });
+            }
+            "INDEX" => {
+                let number = argument(1)?.parse().map_err(|_| invalid())?;
+                let timestamp = argument(2)?;
+                let frames = parse_timestamp(&timestamp).ok_or_else(|| {
+                    InvalidTimestamp(path.to_path_buf(), line_number, timestamp.clone())
+                })?;
+
+                current_track(&mut sheet)
+                    .ok_or_else(invalid)?
+                    .indexes
+                    .push(CueIndex { number, frames });
+            }
+            "FLAGS" => {
+                current_track(&mut sheet)
+                    .ok_or_else(invalid)?
+                    .flags
+                    .extend(tokens[1..].iter().map(|flag| flag.to_uppercase()));
+            }
+            "TITLE" | "PERFORMER" | "ISRC" => {
+                let value = Some(argument(1)?);
+
+                match (current_track(&mut sheet), command.to_uppercase().as_str()) {
+                    (Some(track), "TITLE") => track.title = value,
+                    (Some(track), "PERFORMER") => track.performer = value,
+                    (Some(track), _) => track.isrc = value,
+                    (None, "TITLE") => sheet.title = value,
+                    (None, "PERFORMER") => sheet.performer = value,
+                    (None, _) => {}
+                }
+            }
+            // CATALOG, SONGWRITER, PREGAP, POSTGAP and CDTEXTFILE are not needed
+            _ => {}
+        }
+    }
+
+    Ok(sheet)
+}
+
+fn current_track(sheet: &mut CueSheet) -> Option<&mut CueTrack> {
+    sheet.files.last_mut()?.tracks.last_mut()
+}
+
+/// Splits a line at whitespace, text in double quotes stays one token.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_token = false;
+
+    for c in line.trim().chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_token = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_token {
+                    tokens.push(std::mem::take(&mut current));
+                    has_token = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_token = true;
+            }
+        }
+    }
+
+    if has_token {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+/// Parses `mm:ss:ff` into CD frames.
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let parts = timestamp
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    match parts.as_slice() {
+        [minutes, seconds, frames] if *seconds < 60 && *frames < FRAMES_PER_SECOND => {
+            Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "album.cue";
+
+    #[test]
+    fn parses_pregaps_and_multiple_files() {
+        let content = "\
+REM GENRE Rock
+REM DATE 1999
+PERFORMER \"Some Artist\"
+TITLE \"Some Album\"
+FILE \"CD1.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"First\"
+    FLAGS PRE DCP
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    INDEX 00 03:10:70
+    INDEX 01 03:12:05
+FILE CD2 image.flac WAVE
+  TRACK 03 AUDIO
+    PERFORMER \"Guest\"
+    INDEX 00 00:00:00
+    INDEX 01 00:02:00
+";
+
+        let sheet = parse_cue_sheet(Path::new(PATH), content).unwrap();
+
+        assert_eq!(sheet.title.as_deref(), Some("Some Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("Some Artist"));
+        assert_eq!(
+            sheet.remarks,
+            vec![
+                ("GENRE".to_string(), "Rock".to_string()),
+                ("DATE".to_string(), "1999".to_string()),
+            ]
+        );
+
+        assert_eq!(sheet.files.len(), 2);
+        assert_eq!(sheet.files[0].name, "CD1.flac");
+        assert_eq!(sheet.files[1].name, "CD2 image.flac");
+        assert_eq!(sheet.files[1].file_type, "WAVE");
+
+        let first = &sheet.files[0].tracks[0];
+        assert_eq!(first.title.as_deref(), Some("First"));
+        assert!(first.has_pre_emphasis());
+        assert_eq!(first.start(), Some(0));
+
+        // The pregap (INDEX 00) is kept but the track starts at INDEX 01
+        let second = &sheet.files[0].tracks[1];
+        assert_eq!(second.indexes.len(), 2);
+        assert_eq!(second.indexes[0].frames, (3 * 60 + 10) * 75 + 70);
+        assert_eq!(second.start(), Some((3 * 60 + 12) * 75 + 5));
+        assert!(!second.has_pre_emphasis());
+
+        let third = &sheet.files[1].tracks[0];
+        assert_eq!(third.number, 3);
+        assert_eq!(third.performer.as_deref(), Some("Guest"));
+        assert_eq!(third.start(), Some(2 * 75));
+    }
+
+    #[test]
+    fn rejects_track_before_file() {
+        let result = parse_cue_sheet(Path::new(PATH), "TRACK 01 AUDIO\n");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        assert_eq!(parse_timestamp("01:02:03"), Some((60 + 2) * 75 + 3));
+        assert_eq!(parse_timestamp("00:60:00"), None);
+        assert_eq!(parse_timestamp("00:00:75"), None);
+        assert_eq!(parse_timestamp("00:00"), None);
+    }
+
+    #[test]
+    fn keeps_quoted_text_together() {
+        assert_eq!(
+            tokenize("  FILE \"A  B.flac\" WAVE "),
+            vec!["FILE", "A  B.flac", "WAVE"]
+        );
+        assert_eq!(tokenize("TITLE \"\""), vec!["TITLE", ""]);
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use metaflac::BlockType;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::cue::error::CueError::{
+    MissingTrackStart, SplitFailed, TrackOutsideOfImage, UnsupportedBitDepth,
+};
+use crate::cue::models::{CueFile, CueSheet, CueTrack, FRAMES_PER_SECOND};
+use crate::cue::parser::read_cue_sheet;
+use crate::fs::util::get_all_files_with_extension;
+use crate::redacted::api::constants::FORBIDDEN_CHARACTERS;
+use crate::redacted::models::ReleaseType::{Flac, Flac24};
+use crate::tags::util::EMPHASIS_TAG_KEYS;
+use crate::transcode::backend::EncoderBackend;
+use crate::transcode::native::{spawn_range_decoder, PcmFormat, RangeDecoder};
+use crate::transcode::util::copy_other_allowed_files;
+use crate::transcode::verify::decoded_length;
+
+/// Tags of the image which only describe a single track, they are not carried over to the split
+/// tracks.
+const TRACK_TAG_KEYS: [&str; 8] = [
+    "TITLE",
+    "TRACKNUMBER",
+    "TRACKTOTAL",
+    "TOTALTRACKS",
+    "ISRC",
+    "CUESHEET",
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_PEAK",
+];
+
+/// A single FLAC holding a whole disc, together with the cue sheet describing its tracks.
+struct CueImage {
+    cue_path: PathBuf,
+    image_path: PathBuf,
+    sheet: CueSheet,
+    file: CueFile,
+}
+
+pub struct SplitRelease {
+    pub images: usize,
+    pub tracks: usize,
+    /// Audio before the first track of an image, which is not part of any track
+    pub skipped_pregaps: Vec<String>,
+}
+
+/// Splits every FLAC image with a cue sheet into one tagged FLAC per track, cut sample-accurate
+/// at the `INDEX 01` of every track. Like rippers do by default, the pregap (`INDEX 00`) of a
+/// track stays at the end of the previous track. Everything else of the release is copied along,
+/// so the split folder can stand in for the source. Returns `None` if the release has no image.
+pub async fn split_cue_images(
+    flac_dir: &PathBuf,
+    split_dir: &PathBuf,
+    backend: Arc<dyn EncoderBackend>,
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<Option<SplitRelease>> {
+    let images = find_cue_images(flac_dir).await?;
+
+    if images.is_empty() {
+        return Ok(None);
+    }
+
+    for flac in get_all_files_with_extension(flac_dir, ".flac").await? {
+        if !images.iter().any(|image| image.image_path == flac) {
+            copy_relative(&flac, flac_dir, split_dir).await?;
+        }
+    }
+
+    // Logs and the cue sheets of regular tracks are still needed to detect pre-emphasis
+    let mut extra_files = get_all_files_with_extension(flac_dir, ".log").await?;
+    extra_files.extend(
+        get_all_files_with_extension(flac_dir, ".cue")
+            .await?
+            .into_iter()
+            .filter(|cue| !images.iter().any(|image| image.cue_path == *cue)),
+    );
+
+    for path in extra_files {
+        copy_relative(&path, flac_dir, split_dir).await?;
+    }
+
+    copy_other_allowed_files(flac_dir, flac_dir, split_dir).await?;
+
+    let mut split_release = SplitRelease {
+        images: images.len(),
+        tracks: 0,
+        skipped_pregaps: vec![],
+    };
+
+    for image in &images {
+        let (tracks, skipped_pregap) = split_image(
+            image,
+            flac_dir,
+            split_dir,
+            backend.as_ref(),
+            semaphore.clone(),
+        )
+        .await?;
+
+        split_release.tracks += tracks;
+        split_release.skipped_pregaps.extend(skipped_pregap);
+    }
+
+    Ok(Some(split_release))
+}
+
+/// A FLAC is an image if a cue sheet next to it lists several tracks for it, either by its name
+/// or, as the cue sheet often still names the original WAV, by being the only untracked FLAC in
+/// the folder.
+async fn find_cue_images(flac_dir: &PathBuf) -> anyhow::Result<Vec<CueImage>> {
+    let flacs = get_all_files_with_extension(flac_dir, ".flac").await?;
+    let mut images: Vec<CueImage> = vec![];
+
+    for cue_path in get_all_files_with_extension(flac_dir, ".cue").await? {
+        let sheet = read_cue_sheet(&cue_path).await?;
+
+        let folder_flacs = flacs
+            .iter()
+            .filter(|flac| flac.parent() == cue_path.parent())
+            .collect::<Vec<&PathBuf>>();
+
+        for file in sheet.files.iter().filter(|file| file.tracks.len() > 1) {
+            let file_stem = Path::new(&file.name).file_stem();
+
+            let mut image_path = folder_flacs
+                .iter()
+                .find(|flac| flac.file_stem() == file_stem)
+                .map(|flac| flac.to_path_buf());
+
+            if image_path.is_none() && sheet.files.len() == 1 && folder_flacs.len() == 1 {
+                let flac = folder_flacs[0].clone();
+
+                if !has_track_number(&flac).await? {
+                    image_path = Some(flac);
+                }
+            }
+
+            let Some(image_path) = image_path else {
+                continue;
+            };
+
+            if images.iter().any(|image| image.image_path == image_path) {
+                continue;
+            }
+
+            images.push(CueImage {
+                cue_path: cue_path.clone(),
+                image_path,
+                sheet: sheet.clone(),
+                file: file.clone(),
+            });
+        }
+    }
+
+    Ok(images)
+}
+
+/// Returns how many tracks were split out of the image and the skipped audio before the first
+/// track, if there is any.
+async fn split_image(
+    image: &CueImage,
+    flac_dir: &Path,
+    split_dir: &Path,
+    backend: &dyn EncoderBackend,
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<(usize, Option<String>)> {
+    let image_path_cloned = image.image_path.clone();
+    let streaminfo = tokio::task::spawn_blocking(move || FlacReader::open(image_path_cloned))
+        .await??
+        .streaminfo();
+
+    let total_samples = match streaminfo.samples {
+        Some(samples) => samples,
+        None => decoded_length(&image.image_path).await?.samples,
+    };
+
+    let starts = image
+        .file
+        .tracks
+        .iter()
+        .map(|track| {
+            track
+                .start()
+                .map(|frames| frames_to_samples(frames, streaminfo.sample_rate))
+                .ok_or_else(|| MissingTrackStart(image.cue_path.clone(), track.number))
+        })
+        .collect::<Result<Vec<u64>, _>>()?;
+
+    let mut ranges = vec![];
+
+    for (position, track) in image.file.tracks.iter().enumerate() {
+        let start = starts[position];
+        let end = starts.get(position + 1).copied().unwrap_or(total_samples);
+
+        if start >= end || end > total_samples {
+            return Err(TrackOutsideOfImage(
+                image.cue_path.clone(),
+                track.number,
+                image.image_path.clone(),
+            )
+            .into());
+        }
+
+        ranges.push((track, start, end));
+    }
+
+    let skipped_pregap = match starts.first() {
+        Some(first_start) if *first_start > 0 => Some(format!(
+            "{:.2}s before track {} of \"{}\"",
+            *first_start as f64 / streaminfo.sample_rate as f64,
+            image.file.tracks[0].number,
+            image.image_path.file_name().unwrap().to_str().unwrap()
+        )),
+        _ => None,
+    };
+
+    // Sources which are not 16 or 24 bit are padded to the next of those, which keeps every sample
+    let bits_per_sample = match streaminfo.bits_per_sample {
+        ..=16 => 16,
+        17..=24 => 24,
+        bits => return Err(UnsupportedBitDepth(image.image_path.clone(), bits).into()),
+    };
+
+    let pcm_format = PcmFormat {
+        sample_rate: streaminfo.sample_rate,
+        channels: streaminfo.channels,
+        bits_per_sample,
+    };
+
+    let relative_folder = image.image_path.parent().unwrap().strip_prefix(flac_dir)?;
+    let output_folder = split_dir.join(relative_folder);
+
+    fs::create_dir_all(&output_folder).await?;
+
+    let image_path_cloned = image.image_path.clone();
+    let image_tag =
+        tokio::task::spawn_blocking(move || metaflac::Tag::read_from_path(image_path_cloned))
+            .await??;
+    let image_comments = image_tag
+        .vorbis_comments()
+        .map(|comments| comments.comments.clone())
+        .unwrap_or_default();
+
+    let _permit = semaphore.acquire().await?;
+
+    // The image is decoded only once, its tracks are encoded one after another as it goes
+    let RangeDecoder { handle, receivers } = spawn_range_decoder(
+        image.image_path.clone(),
+        ranges
+            .iter()
+            .map(|(_, start, end)| (*start, *end))
+            .collect(),
+        pcm_format.bits_per_sample,
+    );
+
+    for ((track, _, _), receiver) in ranges.iter().zip(receivers) {
+        let tags = track_tags(&image_comments, &image.sheet, &image.file, track);
+        let title = tags["TITLE"][0].replace(&FORBIDDEN_CHARACTERS[..], "_");
+        let output_path = output_folder.join(format!("{:02} - {}.flac", track.number, title));
+
+        split_track(
+            &image.image_path,
+            &output_path,
+            track.number,
+            receiver,
+            &pcm_format,
+            backend,
+        )
+        .await?;
+        write_track_tags(&output_path, tags).await?;
+    }
+
+    handle.await??;
+
+    Ok((ranges.len(), skipped_pregap))
+}
+
+/// Encodes the decoded range of the image with the FLAC encoder of the backend, the end of the
+/// range is exclusive so neighbouring tracks share no sample.
+async fn split_track(
+    image_path: &Path,
+    output_path: &Path,
+    track_number: u32,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    pcm_format: &PcmFormat,
+    backend: &dyn EncoderBackend,
+) -> anyhow::Result<()> {
+    let format = if pcm_format.bits_per_sample == 16 {
+        Flac
+    } else {
+        Flac24
+    };
+
+    let (mut cmd, _) = backend.encoder(format, output_path, Some(pcm_format));
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::null());
+    cmd.stderr(Stdio::piped());
+    cmd.kill_on_drop(true);
+
+    let mut child = cmd.spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+
+    // Drained while encoding so a chatty encoder can not stall on a full pipe
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_handle = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).await?;
+        Ok::<Vec<u8>, std::io::Error>(buffer)
+    });
+
+    while let Some(pcm) = receiver.recv().await {
+        // The encoder died, its exit status tells why
+        if stdin.write_all(&pcm).await.is_err() {
+            break;
+        }
+    }
+
+    // Closing stdin lets the encoder finish the file, dropping the receiver stops the decoder
+    drop(stdin);
+    drop(receiver);
+
+    let status = child.wait().await?;
+    let stderr = stderr_handle.await??;
+
+    if !status.success() {
+        return Err(SplitFailed(
+            image_path.to_path_buf(),
+            track_number,
+            String::from_utf8_lossy(&stderr).trim().to_string(),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// CD frames (1/75s) are a whole number of samples at 44.1kHz and 48kHz, at other rates (e.g.
+/// 32kHz) the position is rounded to the nearest sample.
+fn frames_to_samples(frames: u64, sample_rate: u32) -> u64 {
+    (frames * sample_rate as u64 + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND
+}
+
+/// The tags of a split track are the album tags of the image, completed and overridden by what
+/// the cue sheet knows about the track.
+fn track_tags(
+    image_comments: &HashMap<String, Vec<String>>,
+    sheet: &CueSheet,
+    file: &CueFile,
+    track: &CueTrack,
+) -> HashMap<String, Vec<String>> {
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+
+    for (key, values) in image_comments {
+        let key = key.to_ascii_uppercase();
+
+        if !TRACK_TAG_KEYS.contains(&key.as_str()) {
+            tags.entry(key).or_default().extend(values.iter().cloned());
+        }
+    }
+
+    let remark = |key: &str| {
+        sheet
+            .remarks
+            .iter()
+            .find(|(remark_key, _)| remark_key == key)
+            .map(|(_, value)| value.clone())
+    };
+
+    let fallbacks = [
+        ("ARTIST", sheet.performer.clone()),
+        ("ALBUMARTIST", sheet.performer.clone()),
+        ("ALBUM", sheet.title.clone()),
+        ("DATE", remark("DATE")),
+        ("GENRE", remark("GENRE")),
+        ("DISCNUMBER", remark("DISCNUMBER")),
+    ];
+
+    for (key, value) in fallbacks {
+        if let Some(value) = value {
+            tags.entry(key.to_string()).or_insert_with(|| vec![value]);
+        }
+    }
+
+    let title = track
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Track {:02}", track.number));
+
+    tags.insert("TITLE".to_string(), vec![title]);
+    tags.insert("TRACKNUMBER".to_string(), vec![track.number.to_string()]);
+    tags.insert(
+        "TRACKTOTAL".to_string(),
+        vec![file.tracks.len().to_string()],
+    );
+
+    if let Some(performer) = &track.performer {
+        tags.insert("ARTIST".to_string(), vec![performer.clone()]);
+    }
+
+    if let Some(isrc) = &track.isrc {
+        tags.insert("ISRC".to_string(), vec![isrc.clone()]);
+    }
+
+    // Keeps the FLAGS PRE of the cue sheet, which is not copied along with the image
+    if track.has_pre_emphasis() {
+        tags.insert(EMPHASIS_TAG_KEYS[0].to_string(), vec!["1".to_string()]);
+    }
+
+    tags
+}
+
+async fn write_track_tags(path: &Path, tags: HashMap<String, Vec<String>>) -> anyhow::Result<()> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let mut tag = metaflac::Tag::read_from_path(&path)?;
+
+        // The cue sheet of the image would be wrong for a single track
+        tag.remove_blocks(BlockType::CueSheet);
+        tag.vorbis_comments_mut().comments = tags;
+
+        tag.save()?;
+
+        Ok(())
+    })
+    .await?
+}
+
+async fn has_track_number(path: &Path) -> anyhow::Result<bool> {
+    let path = path.to_path_buf();
+    let tag = tokio::task::spawn_blocking(move || metaflac::Tag::read_from_path(path)).await??;
+
+    Ok(tag.vorbis_comments().is_some_and(|comments| {
+        comments
+            .comments
+            .keys()
+            .any(|key| key.eq_ignore_ascii_case("TRACKNUMBER"))
+    }))
+}
+
+async fn copy_relative(path: &Path, from_dir: &Path, to_dir: &Path) -> anyhow::Result<()> {
+    let destination = to_dir.join(path.strip_prefix(from_dir)?);
+
+    fs::create_dir_all(destination.parent().unwrap()).await?;
+    fs::copy(path, destination).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_cd_frames_exactly_at_cd_and_dvd_rates() {
+        assert_eq!(frames_to_samples(75, 44100), 44100);
+        assert_eq!(frames_to_samples(1, 44100), 588);
+        assert_eq!(frames_to_samples(1, 48000), 640);
+        assert_eq!(frames_to_samples(1, 96000), 1280);
+    }
+
+    #[test]
+    fn rounds_cd_frames_to_nearest_sample() {
+        // 426.67 and 853.33 samples
+        assert_eq!(frames_to_samples(1, 32000), 427);
+        assert_eq!(frames_to_samples(2, 32000), 853);
+        assert_eq!(frames_to_samples(75, 32000), 32000);
+    }
+}
@@ -14,6 +14,21 @@ pub fn get_lame_executable() -> String {
     "lame.exe".to_string()
 }
 
+#[cfg(target_os = "windows")]
+pub fn get_ffmpeg_executable() -> String {
+    "ffmpeg.exe".to_string()
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_fdkaac_executable() -> String {
+    "fdkaac.exe".to_string()
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_opusenc_executable() -> String {
+    "opusenc.exe".to_string()
+}
+
 #[cfg(target_os = "windows")]
 pub fn get_imdl_executable_name() -> String {
     "imdl.exe".to_string()
@@ -35,6 +50,21 @@ pub fn get_lame_executable() -> String {
     "lame".to_string()
 }
 
+#[cfg(not(target_os = "windows"))]
+pub fn get_ffmpeg_executable() -> String {
+    "ffmpeg".to_string()
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn get_fdkaac_executable() -> String {
+    "fdkaac".to_string()
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn get_opusenc_executable() -> String {
+    "opusenc".to_string()
+}
+
 #[cfg(not(target_os = "windows"))]
 pub fn get_imdl_executable_name() -> String {
     "imdl".to_string()
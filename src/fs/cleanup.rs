@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref PATHS_TO_CLEANUP: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Removes the guarded file or directory once dropped, the path is also tracked globally so it
+/// can be removed when the process gets interrupted.
+pub struct CleanupGuard {
+    path: PathBuf,
+}
+
+impl CleanupGuard {
+    pub fn new(path: PathBuf) -> Self {
+        PATHS_TO_CLEANUP.lock().unwrap().insert(path.clone());
+
+        Self { path }
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        remove_path(&self.path);
+
+        PATHS_TO_CLEANUP.lock().unwrap().remove(&self.path);
+    }
+}
+
+pub fn cleanup_all() {
+    let paths = PATHS_TO_CLEANUP
+        .lock()
+        .unwrap()
+        .drain()
+        .collect::<Vec<PathBuf>>();
+
+    for path in paths {
+        remove_path(&path);
+    }
+}
+
+fn remove_path(path: &PathBuf) {
+    if path.is_dir() {
+        let _ = std::fs::remove_dir_all(path);
+    } else {
+        let _ = std::fs::remove_file(path);
+    }
+}
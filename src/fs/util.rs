@@ -27,3 +27,68 @@ pub async fn get_all_files_with_extension(
 
     return Ok(files);
 }
+
+/// Decodes text files written by rippers, which are UTF-8 or UTF-16 with a BOM, or UTF-8 or
+/// Latin-1 without one.
+pub fn decode_text(bytes: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units = bytes
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]))
+            .collect::<Vec<u16>>();
+
+        String::from_utf16_lossy(&units)
+    };
+
+    if let Some(rest) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        return String::from_utf8_lossy(rest).to_string();
+    }
+
+    if let Some(rest) = bytes.strip_prefix(&[0xff, 0xfe]) {
+        return utf16(rest, u16::from_le_bytes);
+    }
+
+    if let Some(rest) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        return utf16(rest, u16::from_be_bytes);
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|byte| *byte as char).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_utf16_with_bom() {
+        let text = "TITLE \"Café\"";
+
+        let mut little_endian = vec![0xff, 0xfe];
+        little_endian.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+
+        let mut big_endian = vec![0xfe, 0xff];
+        big_endian.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+
+        assert_eq!(decode_text(&little_endian), text);
+        assert_eq!(decode_text(&big_endian), text);
+    }
+
+    #[test]
+    fn decodes_utf8_with_and_without_bom() {
+        let text = "PERFORMER \"Björk\"";
+
+        let mut with_bom = vec![0xef, 0xbb, 0xbf];
+        with_bom.extend(text.as_bytes());
+
+        assert_eq!(decode_text(&with_bom), text);
+        assert_eq!(decode_text(text.as_bytes()), text);
+    }
+
+    #[test]
+    fn falls_back_to_latin1() {
+        assert_eq!(decode_text(b"Bj\xf6rk"), "Björk");
+    }
+}
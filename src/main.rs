@@ -2,22 +2,27 @@ use clap::{arg, Parser, Subcommand};
 use command::self_update;
 use console::Term;
 use log::debug;
+use std::collections::HashMap;
 use std::mem::discriminant;
 use std::path::PathBuf;
 use updater::release;
 
+use crate::config::models::EncoderProfile;
 use crate::github::api::GithubApi;
 use crate::redacted::models::ReleaseType;
+use crate::transcode::backend::EncoderBackendType;
 use crate::updater::constants::{GH_REPO, GH_USER};
 use crate::updater::release::ReleaseVersionCompareResult;
 
 mod command;
 mod config;
+mod cue;
 mod ext_deps;
 mod fs;
 mod github;
 mod imdl;
 mod redacted;
+mod scheduler;
 mod spectrogram;
 mod tags;
 mod transcode;
@@ -90,7 +95,7 @@ pub struct TranscodeCommand {
     #[arg(long, short)]
     pub config_file: Option<PathBuf>,
 
-    /// List of allowed formats to transcode to, defaults to all formats if omitted
+    /// List of allowed formats to transcode to, defaults to all formats if omitted. RED does not allow opus, it is only transcoded and gets no .torrent
     #[arg(long, short = 'f')]
     pub allowed_transcode_formats: Vec<ReleaseType>,
 
@@ -110,6 +115,42 @@ pub struct TranscodeCommand {
     #[arg(long, default_value = "false")]
     pub skip_spectrogram: bool,
 
+    /// If sources the spectral analysis finds to be lossy masters with high confidence should be skipped without asking, the analysis also runs when the spectrogram check is skipped so unattended runs can use it
+    #[arg(long, default_value = "false")]
+    pub reject_lossy: bool,
+
+    /// If an already existing transcode output should be resumed instead of aborting, tracks that are already complete are kept and only missing or broken ones are transcoded again, interrupted runs and failed resumed runs keep their partial output
+    #[arg(long, default_value = "false")]
+    pub resume: bool,
+
+    /// If the external flac and sox binaries should be used to decode and resample instead of the built-in decoder, useful to reproduce transcodes made with older versions
+    #[arg(long, default_value = "false")]
+    pub external_decoder: bool,
+
+    /// If 24bit sources above 48kHz should additionally be transcoded to a 24bit FLAC downsampled to 44.1kHz or 48kHz, this adds flac24 to the allowed formats
+    #[arg(long, default_value = "false")]
+    pub downsample_24bit: bool,
+
+    /// If multichannel sources should be downmixed to stereo with the ITU coefficients instead of being skipped, the downmix is scaled down to prevent clipping and noted in the description
+    #[arg(long, default_value = "false")]
+    pub downmix: bool,
+
+    /// If the track and album loudness of the FLAC and MP3 transcodes should be measured (EBU R128) and written as ReplayGain tags, peaks and clipping are shown in the release summary
+    #[arg(long, default_value = "false")]
+    pub replaygain: bool,
+
+    /// Which programs encode the transcodes (and decode them if the external decoder is used), tools uses flac, lame and sox while ffmpeg does everything with ffmpeg, defaults to tools
+    #[arg(long, value_enum)]
+    pub encoder_backend: Option<EncoderBackendType>,
+
+    /// The name of an encoder profile from the config file whose command templates should be used instead of the encoder backend
+    #[arg(long)]
+    pub encoder_profile: Option<String>,
+
+    /// The encoder profiles defined in the config file
+    #[arg(skip)]
+    pub encoder_profiles: HashMap<String, EncoderProfile>,
+
     /// If this is a dry run, no files will be uploaded to Redacted
     #[arg(long, short, default_value = "false")]
     pub dry_run: bool,
@@ -16,6 +16,9 @@ pub enum ReleaseType {
     Flac,
     Mp3320,
     Mp3V0,
+    Mp3V2,
+    Aac256,
+    Opus,
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone, Copy)]
@@ -188,6 +191,9 @@ impl fmt::Display for ReleaseType {
             ReleaseType::Flac => write!(f, "FLAC"),
             ReleaseType::Mp3320 => write!(f, "MP3 320"),
             ReleaseType::Mp3V0 => write!(f, "MP3 V0"),
+            ReleaseType::Mp3V2 => write!(f, "MP3 V2"),
+            ReleaseType::Aac256 => write!(f, "AAC 256"),
+            ReleaseType::Opus => write!(f, "Opus"),
         }
     }
 }
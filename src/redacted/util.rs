@@ -1,12 +1,37 @@
 use crate::built_info;
 
-pub fn create_description(original_torrent_perma_url: String, transcode_command: String) -> String {
+pub fn create_description(
+    original_torrent_perma_url: String,
+    transcode_command: String,
+    encoder_settings: Option<String>,
+    notes: &[String],
+) -> String {
+    let encoder_settings = match encoder_settings {
+        Some(encoder_settings) => format!(
+            "Encoder settings (verified from the LAME header):\n[code]{}[/code]\n",
+            encoder_settings
+        ),
+        None => "".to_string(),
+    };
+
+    let notes = notes
+        .iter()
+        .map(|note| format!("{}\n", note))
+        .collect::<String>();
+
     return format!(
-        "Transcode of [url]{}[/url]\n\nTranscode process:\n[code]{}[/code]\nCreated using [url=https://github.com/DevYukine/red_oxide]red_oxide v{} by DevYukine[/url]",
-        original_torrent_perma_url, transcode_command, built_info::PKG_VERSION
+        "Transcode of [url]{}[/url]\n\nTranscode process:\n[code]{}[/code]\n{}{}Created using [url=https://github.com/DevYukine/red_oxide]red_oxide v{} by DevYukine[/url]",
+        original_torrent_perma_url, transcode_command, encoder_settings, notes, built_info::PKG_VERSION
     );
 }
 
+pub fn report_link(torrent_id: i64) -> String {
+    format!(
+        "https://redacted.sh/reportsv2.php?action=report&id={}",
+        torrent_id
+    )
+}
+
 pub fn perma_link(group_id: i64, torrent_id: i64) -> String {
     return format!(
         "https://redacted.sh/torrents.php?id={}&torrentid={}#torrent{}",
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::redacted::api::client::RedactedApi;
+
+/// Shared state of all releases that are processed at the same time, so they share one
+/// rate-limited API client, one concurrency limit for CPU heavy work and one terminal.
+pub struct Scheduler {
+    pub api: Mutex<RedactedApi>,
+    pub semaphore: Arc<Semaphore>,
+    pub multi_progress: MultiProgress,
+    prompt_lock: Mutex<()>,
+}
+
+impl Scheduler {
+    pub fn new(api: RedactedApi, concurrency: usize) -> Self {
+        Self {
+            api: Mutex::new(api),
+            semaphore: Arc::new(Semaphore::new(concurrency)),
+            multi_progress: MultiProgress::with_draw_target(ProgressDrawTarget::stdout()),
+            prompt_lock: Mutex::new(()),
+        }
+    }
+
+    /// Queues an interactive prompt, only one prompt is shown at a time and the progress bars of
+    /// the other releases are hidden while it is on screen.
+    pub async fn prompt<T, F>(&self, prompt: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    {
+        let _guard = self.prompt_lock.lock().await;
+
+        self.multi_progress
+            .set_draw_target(ProgressDrawTarget::hidden());
+
+        let result = tokio::task::spawn_blocking(prompt).await;
+
+        self.multi_progress
+            .set_draw_target(ProgressDrawTarget::stdout());
+
+        result?
+    }
+}
@@ -0,0 +1,442 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use futures::future::join_all;
+use realfft::RealFftPlanner;
+use tokio::sync::Semaphore;
+
+use crate::fs::util::get_all_files_with_extension;
+
+const FFT_SIZE: usize = 4096;
+
+/// The spectrum is judged in bands of this width, single bins are too noisy.
+const BAND_WIDTH: f64 = 250.0;
+
+/// Windows quieter than this (RMS in dBFS) only show the noise floor and are left out.
+const SILENT_WINDOW_LEVEL: f64 = -60.0;
+
+/// How far a band has to be above the noise floor to count as content.
+const CONTENT_MARGIN: f64 = 15.0;
+
+/// A cutoff this close to the Nyquist frequency is the anti-aliasing filter of the ADC.
+const FULL_BAND_MARGIN: f64 = 1000.0;
+
+/// How far the level has to drop around the cutoff (in dB) for a shelf, natural roll-offs of
+/// old recordings are much gentler. Lossy encoders and resamplers drop far more.
+const STEEP_DROP: f64 = 20.0;
+const OBVIOUS_DROP: f64 = 40.0;
+
+/// The lowpass shelves of common lossy encoders, as ranges of the cutoff in Hz.
+const LOSSY_SHELVES: [(f64, f64); 3] = [(15500.0, 16500.0), (18500.0, 19500.0), (19500.0, 20500.0)];
+
+/// Sample rates an upsampled source may originally have had.
+const ORIGINAL_SAMPLE_RATES: [u32; 4] = [44100, 48000, 88200, 96000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Confidence::Low => write!(f, "low"),
+            Confidence::Medium => write!(f, "medium"),
+            Confidence::High => write!(f, "high"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// Content up to the Nyquist frequency or a gentle natural roll-off
+    Clean,
+    /// A steep lowpass like lossy encoders apply
+    Lossy { cutoff: f64 },
+    /// Nothing above the Nyquist frequency of a lower sample rate
+    Upsampled {
+        cutoff: f64,
+        original_sample_rate: u32,
+    },
+    /// Too quiet to judge
+    Inconclusive,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackAnalysis {
+    pub path: PathBuf,
+    pub verdict: Verdict,
+    pub confidence: Confidence,
+    /// How far the level drops around the cutoff in dB
+    pub drop: f64,
+}
+
+impl TrackAnalysis {
+    pub fn is_suspicious(&self) -> bool {
+        matches!(
+            self.verdict,
+            Verdict::Lossy { .. } | Verdict::Upsampled { .. }
+        )
+    }
+}
+
+impl fmt::Display for TrackAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.verdict {
+            Verdict::Clean => write!(f, "clean")?,
+            Verdict::Lossy { cutoff } => write!(
+                f,
+                "lossy, cutoff at {:.1}kHz ({:.0}dB drop)",
+                cutoff / 1000.0,
+                self.drop
+            )?,
+            Verdict::Upsampled {
+                cutoff,
+                original_sample_rate,
+            } => write!(
+                f,
+                "upsampled from {}kHz, nothing above {:.1}kHz ({:.0}dB drop)",
+                *original_sample_rate as f64 / 1000.0,
+                cutoff / 1000.0,
+                self.drop
+            )?,
+            Verdict::Inconclusive => write!(f, "inconclusive, too quiet")?,
+        }
+
+        write!(f, ", {} confidence", self.confidence)
+    }
+}
+
+pub struct SpectralReport {
+    pub tracks: Vec<TrackAnalysis>,
+}
+
+impl SpectralReport {
+    pub fn suspicious_tracks(&self) -> Vec<&TrackAnalysis> {
+        self.tracks
+            .iter()
+            .filter(|track| track.is_suspicious())
+            .collect()
+    }
+
+    /// If most tracks have a lossy cutoff with high confidence, which is a lossy master and no
+    /// single odd track.
+    pub fn is_obviously_lossy(&self) -> bool {
+        let lossy_tracks = self
+            .tracks
+            .iter()
+            .filter(|track| {
+                matches!(track.verdict, Verdict::Lossy { .. })
+                    && track.confidence == Confidence::High
+            })
+            .count();
+
+        lossy_tracks * 2 > self.tracks.len()
+    }
+}
+
+/// Decodes every track of the release and judges its averaged spectrum.
+pub async fn analyze_release(
+    flac_dir: &PathBuf,
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<SpectralReport> {
+    let paths = get_all_files_with_extension(flac_dir, ".flac").await?;
+
+    let results = join_all(paths.into_iter().map(|path| {
+        let semaphore = semaphore.clone();
+
+        async move {
+            let _permit = semaphore.acquire().await?;
+            tokio::task::spawn_blocking(move || analyze_track(path)).await?
+        }
+    }))
+    .await;
+
+    let mut tracks = results.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+    tracks.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(SpectralReport { tracks })
+}
+
+fn analyze_track(path: PathBuf) -> anyhow::Result<TrackAnalysis> {
+    let (bands, sample_rate) = match band_levels(&path)? {
+        Some(spectrum) => spectrum,
+        None => {
+            return Ok(TrackAnalysis {
+                path,
+                verdict: Verdict::Inconclusive,
+                confidence: Confidence::Low,
+                drop: 0.0,
+            })
+        }
+    };
+
+    let (verdict, confidence, drop) = classify(&bands, sample_rate);
+
+    Ok(TrackAnalysis {
+        path,
+        verdict,
+        confidence,
+        drop,
+    })
+}
+
+/// Finds the highest band with content and judges the shelf there.
+fn classify(bands: &[f64], sample_rate: u32) -> (Verdict, Confidence, f64) {
+    let nyquist = sample_rate as f64 / 2.0;
+    let floor = bands.iter().copied().fold(f64::INFINITY, f64::min);
+
+    let Some(cutoff_band) = bands
+        .iter()
+        .rposition(|level| *level > floor + CONTENT_MARGIN)
+    else {
+        // A flat spectrum has no cutoff to judge, it is noise or no music at all
+        return (Verdict::Clean, Confidence::Low, 0.0);
+    };
+
+    let cutoff = (cutoff_band + 1) as f64 * BAND_WIDTH;
+
+    if cutoff >= nyquist - FULL_BAND_MARGIN {
+        return (Verdict::Clean, Confidence::High, 0.0);
+    }
+
+    let mean_level = |from: f64, to: f64| {
+        let from = (from / BAND_WIDTH).max(0.0) as usize;
+        let to = ((to / BAND_WIDTH) as usize).clamp(from + 1, bands.len());
+        let levels = &bands[from..to];
+
+        levels.iter().sum::<f64>() / levels.len() as f64
+    };
+
+    let drop =
+        mean_level(cutoff - 1500.0, cutoff - 500.0) - mean_level(cutoff + 500.0, cutoff + 1500.0);
+
+    if drop < STEEP_DROP {
+        // A gentle roll-off is most likely the recording, but might hide a lossy source
+        let confidence = if cutoff < LOSSY_SHELVES[1].0 {
+            Confidence::Low
+        } else {
+            Confidence::Medium
+        };
+
+        return (Verdict::Clean, confidence, drop);
+    }
+
+    let confidence = if drop >= OBVIOUS_DROP {
+        Confidence::High
+    } else {
+        Confidence::Medium
+    };
+
+    let on_lossy_shelf = LOSSY_SHELVES
+        .iter()
+        .any(|(from, to)| (*from..=*to).contains(&cutoff));
+
+    // Below 48kHz a shelf is far more likely a lossy encoder than a resampler
+    if on_lossy_shelf && sample_rate <= 48000 {
+        return (Verdict::Lossy { cutoff }, confidence, drop);
+    }
+
+    // The band between the original and the current Nyquist frequency is empty
+    let original_sample_rate = ORIGINAL_SAMPLE_RATES
+        .iter()
+        .copied()
+        .filter(|rate| *rate < sample_rate)
+        .find(|rate| {
+            let original_nyquist = *rate as f64 / 2.0;
+            cutoff > original_nyquist * 0.85 && cutoff <= original_nyquist + BAND_WIDTH * 2.0
+        });
+
+    if let Some(original_sample_rate) = original_sample_rate {
+        return (
+            Verdict::Upsampled {
+                cutoff,
+                original_sample_rate,
+            },
+            confidence,
+            drop,
+        );
+    }
+
+    // Steep cutoffs elsewhere are unusual, but not typical for lossy encoders
+    let confidence = if on_lossy_shelf {
+        confidence
+    } else {
+        Confidence::Low
+    };
+
+    (Verdict::Lossy { cutoff }, confidence, drop)
+}
+
+/// The power spectrum of the track averaged over all windows which are not silent, in dB per
+/// band. `None` if the whole track is silent.
+fn band_levels(path: &PathBuf) -> anyhow::Result<Option<(Vec<f64>, u32)>> {
+    let mut reader = FlacReader::open(path)?;
+    let streaminfo = reader.streaminfo();
+    let channels = streaminfo.channels as usize;
+    let scale = 1.0 / (1i64 << (streaminfo.bits_per_sample - 1)) as f64;
+
+    let fft = RealFftPlanner::<f64>::new().plan_fft_forward(FFT_SIZE);
+    let mut input = fft.make_input_vec();
+    let mut output = fft.make_output_vec();
+
+    let hann = (0..FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / FFT_SIZE as f64).cos())
+        .collect::<Vec<f64>>();
+
+    let mut power = vec![0.0; FFT_SIZE / 2 + 1];
+    let mut windows = 0;
+    let mut pending = vec![Vec::with_capacity(FFT_SIZE); channels];
+
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+
+    while let Some(block) = blocks.read_next_or_eof(buffer)? {
+        for i in 0..block.duration() {
+            for (channel, samples) in pending.iter_mut().enumerate() {
+                samples.push(block.sample(channel as u32, i) as f64 * scale);
+            }
+
+            if pending[0].len() < FFT_SIZE {
+                continue;
+            }
+
+            let mean_square = pending
+                .iter()
+                .flatten()
+                .map(|sample| sample * sample)
+                .sum::<f64>()
+                / (FFT_SIZE * channels) as f64;
+
+            if 10.0 * mean_square.log10() > SILENT_WINDOW_LEVEL {
+                for samples in &pending {
+                    for ((input, sample), weight) in input.iter_mut().zip(samples).zip(&hann) {
+                        *input = sample * weight;
+                    }
+
+                    fft.process(&mut input, &mut output)
+                        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+                    for (power, bin) in power.iter_mut().zip(&output) {
+                        *power += bin.norm_sqr();
+                    }
+                }
+
+                windows += 1;
+            }
+
+            pending.iter_mut().for_each(|samples| samples.clear());
+        }
+
+        buffer = block.into_buffer();
+    }
+
+    if windows == 0 {
+        return Ok(None);
+    }
+
+    let bin_width = streaminfo.sample_rate as f64 / FFT_SIZE as f64;
+    let band_count = ((streaminfo.sample_rate as f64 / 2.0) / BAND_WIDTH).ceil() as usize;
+    let mut bands = vec![(0.0, 0); band_count];
+
+    for (bin, power) in power.iter().enumerate().skip(1) {
+        let band = ((bin as f64 * bin_width / BAND_WIDTH) as usize).min(band_count - 1);
+        bands[band].0 += power / windows as f64;
+        bands[band].1 += 1;
+    }
+
+    // Digital silence is clamped so it does not end up as -inf
+    let levels = bands
+        .into_iter()
+        .map(|(power, bins): (f64, usize)| 10.0 * (power / bins.max(1) as f64 + 1e-20).log10())
+        .collect();
+
+    Ok(Some((levels, streaminfo.sample_rate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bands at `level` up to `cutoff` and at `floor` above it.
+    fn bands(sample_rate: u32, cutoff: f64, level: f64, floor: f64) -> Vec<f64> {
+        let band_count = ((sample_rate as f64 / 2.0) / BAND_WIDTH).ceil() as usize;
+
+        (0..band_count)
+            .map(|band| {
+                if (band as f64) * BAND_WIDTH < cutoff {
+                    level
+                } else {
+                    floor
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_band_is_clean() {
+        let (verdict, confidence, _) = classify(&bands(44100, 22000.0, -30.0, -60.0), 44100);
+
+        assert_eq!(verdict, Verdict::Clean);
+        assert_eq!(confidence, Confidence::High);
+    }
+
+    #[test]
+    fn flat_spectrum_is_clean_with_low_confidence() {
+        let (verdict, confidence, drop) = classify(&vec![-30.0; 89], 44100);
+
+        assert_eq!(verdict, Verdict::Clean);
+        assert_eq!(confidence, Confidence::Low);
+        assert_eq!(drop, 0.0);
+    }
+
+    #[test]
+    fn steep_shelf_at_16khz_is_lossy() {
+        let (verdict, confidence, drop) = classify(&bands(44100, 16000.0, -30.0, -90.0), 44100);
+
+        assert_eq!(verdict, Verdict::Lossy { cutoff: 16000.0 });
+        assert_eq!(confidence, Confidence::High);
+        assert_eq!(drop, 60.0);
+    }
+
+    #[test]
+    fn shelf_of_lower_rate_is_upsampled() {
+        let (verdict, confidence, _) = classify(&bands(96000, 22000.0, -30.0, -100.0), 96000);
+
+        assert_eq!(
+            verdict,
+            Verdict::Upsampled {
+                cutoff: 22000.0,
+                original_sample_rate: 44100,
+            }
+        );
+        assert_eq!(confidence, Confidence::High);
+    }
+
+    #[test]
+    fn gentle_roll_off_is_clean() {
+        // Falls by 1dB per band above 12kHz, far gentler than a lowpass, and fades into the
+        // floor below 18.5kHz where it might hide a lossy shelf
+        let bands = (0..89)
+            .map(|band| -30.0 - (band as f64 - 48.0).max(0.0))
+            .collect::<Vec<f64>>();
+
+        let (verdict, confidence, drop) = classify(&bands, 44100);
+
+        assert_eq!(verdict, Verdict::Clean);
+        assert_eq!(confidence, Confidence::Low);
+        assert!(drop < STEEP_DROP);
+    }
+
+    #[test]
+    fn odd_steep_cutoff_is_lossy_with_low_confidence() {
+        let (verdict, confidence, _) = classify(&bands(44100, 13000.0, -30.0, -90.0), 44100);
+
+        assert_eq!(verdict, Verdict::Lossy { cutoff: 13000.0 });
+        assert_eq!(confidence, Confidence::Low);
+    }
+}
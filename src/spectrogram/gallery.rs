@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use claxon::FlacReader;
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use tokio::fs;
+
+use crate::fs::util::get_all_files_with_extension;
+
+const FULL_SUFFIX: &str = ".spectrogram-full.png";
+const ZOOM_SUFFIX: &str = ".spectrogram-zoom.png";
+
+const STYLE: &str = "body{background:#111;color:#ddd;font-family:sans-serif;margin:2em}\
+h2{border-bottom:1px solid #444;padding-bottom:.3em}\
+.track{margin-bottom:2em}\
+.track h3{margin:.2em 0}\
+.info{color:#999;font-size:.9em;margin-bottom:.5em}\
+.images{display:flex;gap:1em;align-items:flex-start}\
+.images img{max-width:100%;height:auto}\
+.full{flex:6}.zoom{flex:1}";
+
+struct GalleryTrack {
+    disc: String,
+    track_number: Option<u32>,
+    file_name: String,
+    /// The spectrograms relative to the gallery, without their suffix
+    image_base: String,
+    sample_rate: u32,
+    bits_per_sample: u32,
+}
+
+/// Writes an `index.html` into the spectrogram folder of the release, showing the full and the
+/// zoomed spectrogram of every track in order, grouped by disc. Discs are the subfolders of the
+/// release (e.g. CD1/CD2) or, for flat releases, the disc number tags.
+pub async fn write_gallery(flac_dir: &Path, spectrogram_dir: &Path) -> anyhow::Result<PathBuf> {
+    let mut tracks = vec![];
+
+    for path in get_all_files_with_extension(&flac_dir.to_path_buf(), ".flac").await? {
+        let relative_path = path.strip_prefix(flac_dir)?.to_path_buf();
+        let path_cloned = path.clone();
+
+        let (streaminfo, tag) = tokio::task::spawn_blocking(move || {
+            let streaminfo = FlacReader::open(&path_cloned)?.streaminfo();
+            let tag = metaflac::Tag::read_from_path(&path_cloned)?;
+
+            Ok::<_, anyhow::Error>((streaminfo, tag))
+        })
+        .await??;
+
+        let comment = |key: &str| {
+            tag.vorbis_comments().and_then(|comments| {
+                comments
+                    .comments
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                    .and_then(|(_, values)| values.first().cloned())
+            })
+        };
+
+        let folder = relative_path
+            .parent()
+            .map(|parent| parent.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let disc = match (folder.is_empty(), comment("DISCNUMBER")) {
+            (false, _) => folder,
+            (true, Some(disc_number)) => {
+                format!("Disc {}", leading_number(&disc_number).unwrap_or(1))
+            }
+            (true, None) => String::new(),
+        };
+
+        let file_name = relative_path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let image_base = relative_path
+            .with_file_name(file_name.replace(".flac", ""))
+            .to_str()
+            .unwrap()
+            .replace('\\', "/");
+
+        tracks.push(GalleryTrack {
+            disc,
+            track_number: comment("TRACKNUMBER").and_then(|value| leading_number(&value)),
+            image_base,
+            file_name,
+            sample_rate: streaminfo.sample_rate,
+            bits_per_sample: streaminfo.bits_per_sample,
+        });
+    }
+
+    // Discs are ordered by their number, so CD10 comes after CD2
+    let mut discs: BTreeMap<(bool, Option<u32>, String), Vec<GalleryTrack>> = BTreeMap::new();
+
+    for track in tracks {
+        let number = disc_number(&track.disc);
+
+        discs
+            .entry((number.is_none(), number, track.disc.clone()))
+            .or_default()
+            .push(track);
+    }
+
+    let folder_name = flac_dir.file_name().unwrap().to_str().unwrap();
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        encode_text(folder_name),
+        STYLE,
+        encode_text(folder_name)
+    );
+
+    for ((_, _, disc), mut disc_tracks) in discs {
+        disc_tracks.sort_by(|a, b| {
+            (a.track_number.is_none(), a.track_number, &a.file_name).cmp(&(
+                b.track_number.is_none(),
+                b.track_number,
+                &b.file_name,
+            ))
+        });
+
+        if !disc.is_empty() {
+            html.push_str(&format!("<h2>{}</h2>\n", encode_text(&disc)));
+        }
+
+        for track in &disc_tracks {
+            html.push_str(&format!(
+                "<div class=\"track\">\n<h3>{}</h3>\n<div class=\"info\">{}kHz, {}bit</div>\n<div class=\"images\">\n<a class=\"full\" href=\"{full}\"><img src=\"{full}\" alt=\"Full spectrogram\"></a>\n<a class=\"zoom\" href=\"{zoom}\"><img src=\"{zoom}\" alt=\"Zoomed spectrogram\"></a>\n</div>\n</div>\n",
+                encode_text(&track.file_name),
+                track.sample_rate as f64 / 1000.0,
+                track.bits_per_sample,
+                full = image_url(&track.image_base, FULL_SUFFIX),
+                zoom = image_url(&track.image_base, ZOOM_SUFFIX),
+            ));
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+
+    let index_path = spectrogram_dir.join("index.html");
+    fs::write(&index_path, html).await?;
+
+    Ok(index_path)
+}
+
+/// Characters which would end the path part of a relative URL are percent-encoded.
+fn image_url(image_base: &str, suffix: &str) -> String {
+    let url = format!("{}{}", image_base, suffix)
+        .replace('%', "%25")
+        .replace('#', "%23")
+        .replace('?', "%3F");
+
+    encode_double_quoted_attribute(&url).to_string()
+}
+
+/// Disc folders and names carry their number after a prefix, like `CD2` or `Disc 10`.
+fn disc_number(disc: &str) -> Option<u32> {
+    leading_number(disc.trim_start_matches(|c: char| !c.is_ascii_digit()))
+}
+
+/// Track and disc numbers may be written as `1`, `01` or `1/12`.
+fn leading_number(value: &str) -> Option<u32> {
+    let digits = value
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>();
+
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_leading_numbers() {
+        assert_eq!(leading_number("1"), Some(1));
+        assert_eq!(leading_number("01"), Some(1));
+        assert_eq!(leading_number(" 3/12"), Some(3));
+        assert_eq!(leading_number("A1"), None);
+        assert_eq!(leading_number(""), None);
+    }
+
+    #[test]
+    fn reads_disc_numbers_after_prefix() {
+        assert_eq!(disc_number("CD2"), Some(2));
+        assert_eq!(disc_number("Disc 10"), Some(10));
+        assert_eq!(disc_number("Bonus"), None);
+        assert_eq!(disc_number(""), None);
+    }
+
+    #[test]
+    fn keeps_plain_paths_in_urls() {
+        assert_eq!(
+            image_url("CD1/01 - Intro", FULL_SUFFIX),
+            "CD1/01 - Intro.spectrogram-full.png"
+        );
+    }
+
+    #[test]
+    fn encodes_url_delimiters_in_urls() {
+        assert_eq!(
+            image_url("100% #1?", ZOOM_SUFFIX),
+            "100%25 %231%3F.spectrogram-zoom.png"
+        );
+    }
+
+    #[test]
+    fn escapes_quotes_in_urls() {
+        assert_eq!(
+            image_url("\"Live\" & more", FULL_SUFFIX),
+            "&quot;Live&quot; &amp; more.spectrogram-full.png"
+        );
+    }
+}
@@ -1 +1,4 @@
+pub mod analysis;
+pub mod gallery;
+pub mod render;
 pub mod spectrogram;
@@ -0,0 +1,575 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use realfft::{RealFftPlanner, RealToComplex};
+
+use crate::transcode::util::decode_flac_length;
+
+/// The darkest colour is this far below full scale, like `sox spectrogram -z 120`.
+const DYNAMIC_RANGE: f64 = 120.0;
+
+/// Kaiser window for a sidelobe attenuation of the dynamic range.
+const KAISER_BETA: f64 = 0.1102 * (DYNAMIC_RANGE - 8.7);
+
+/// Margins around the plot for the title and the frequency and time labels.
+const LEFT_MARGIN: usize = 48;
+const RIGHT_MARGIN: usize = 8;
+const TOP_MARGIN: usize = 20;
+const BOTTOM_MARGIN: usize = 20;
+
+const BACKGROUND: [u8; 3] = [0, 0, 0];
+const LABEL_COLOR: [u8; 3] = [220, 220, 220];
+const GRID_COLOR: [u8; 3] = [96, 96, 96];
+
+/// Colour stops from -120dB to 0dB, black over blue, purple, red and yellow to white.
+const PALETTE: [(f64, [u8; 3]); 7] = [
+    (0.0, [0, 0, 0]),
+    (0.15, [0, 0, 96]),
+    (0.35, [128, 0, 160]),
+    (0.55, [224, 0, 64]),
+    (0.75, [255, 128, 0]),
+    (0.9, [255, 224, 64]),
+    (1.0, [255, 255, 255]),
+];
+
+/// Frequency gridlines are spaced by the first step giving at most this many lines.
+const MAX_GRIDLINES: u32 = 12;
+const GRIDLINE_STEPS: [u32; 6] = [1000, 2000, 5000, 10000, 20000, 50000];
+
+const TIME_LABELS: usize = 5;
+
+/// Glyphs of 3x5 pixels, one row per byte, drawn at twice their size.
+const GLYPH_SCALE: usize = 2;
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+
+/// Which part of the track the spectrogram shows.
+pub enum Span {
+    Full,
+    /// Seconds from the start of the track and how many seconds are shown
+    Zoom {
+        start: f64,
+        duration: f64,
+    },
+}
+
+/// Renders the spectrogram of the first channel, the plot has one column per `width` and one row
+/// per frequency bin, so `height` decides the FFT size like it does for sox.
+pub fn render_spectrogram(
+    path: &Path,
+    output_path: &Path,
+    span: Span,
+    width: usize,
+    height: usize,
+) -> anyhow::Result<()> {
+    let fft_size = (height - 1) * 2;
+
+    let mut reader = FlacReader::open(path)?;
+    let streaminfo = reader.streaminfo();
+    let sample_rate = streaminfo.sample_rate;
+    let scale = 1.0 / (1i64 << (streaminfo.bits_per_sample - 1)) as f64;
+
+    let total_samples = match streaminfo.samples {
+        Some(samples) => samples,
+        None => decode_flac_length(path)?.samples,
+    };
+
+    let (first_sample, samples_shown) = match span {
+        Span::Full => (0, total_samples),
+        Span::Zoom { start, duration } => {
+            let duration = ((duration * sample_rate as f64) as u64).min(total_samples);
+            let start = ((start * sample_rate as f64) as u64).min(total_samples - duration);
+
+            (start, duration)
+        }
+    };
+
+    let mut analyzer = ColumnAnalyzer::new(fft_size, width, first_sample, samples_shown);
+
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+    let mut position = 0;
+
+    while let Some(block) = blocks.read_next_or_eof(buffer)? {
+        let samples = block.channel(0);
+
+        if position + samples.len() as u64 + fft_size as u64 >= first_sample {
+            analyzer.push(
+                samples.iter().map(|sample| *sample as f64 * scale),
+                position,
+            )?;
+        }
+
+        position += samples.len() as u64;
+        buffer = block.into_buffer();
+
+        if position > first_sample + samples_shown + fft_size as u64 {
+            break;
+        }
+    }
+
+    let columns = analyzer.finish()?;
+
+    let seconds = |sample: u64| sample as f64 / sample_rate as f64;
+    let time_range = (seconds(first_sample), seconds(first_sample + samples_shown));
+
+    // Titled with the file name like `sox spectrogram -t`
+    let title = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    write_png(
+        output_path,
+        &title,
+        &columns,
+        height,
+        sample_rate,
+        time_range,
+    )
+}
+
+/// Averages the power spectra of all windows centered in a column. Windows are spread out so
+/// every column gets at least one, long tracks get several per column without any overlap.
+struct ColumnAnalyzer {
+    fft: Arc<dyn RealToComplex<f64>>,
+    window: Vec<f64>,
+    window_gain: f64,
+    width: usize,
+    first_sample: u64,
+    samples_shown: u64,
+    hop: u64,
+    /// The samples of the next window so far, starting at `window_start` of the track
+    pending: Vec<f64>,
+    window_start: i64,
+    power: Vec<Vec<f64>>,
+    windows: Vec<usize>,
+}
+
+impl ColumnAnalyzer {
+    fn new(fft_size: usize, width: usize, first_sample: u64, samples_shown: u64) -> Self {
+        let fft = RealFftPlanner::<f64>::new().plan_fft_forward(fft_size);
+        let window = kaiser_window(fft_size);
+        let window_gain = window.iter().sum::<f64>();
+
+        let hop = (samples_shown / width as u64).clamp(1, fft_size as u64);
+        let window_start = first_sample as i64 + (hop / 2) as i64 - (fft_size / 2) as i64;
+
+        // Windows reaching in front of the track are padded with silence
+        let pending = vec![0.0; (-window_start).max(0) as usize];
+
+        Self {
+            fft,
+            window,
+            window_gain,
+            width,
+            first_sample,
+            samples_shown,
+            hop,
+            pending,
+            window_start,
+            power: vec![vec![0.0; fft_size / 2 + 1]; width],
+            windows: vec![0; width],
+        }
+    }
+
+    /// Takes the next samples of the track starting at `position`, samples in front of the next
+    /// window are skipped.
+    fn push(&mut self, samples: impl Iterator<Item = f64>, position: u64) -> anyhow::Result<()> {
+        for (offset, sample) in samples.enumerate() {
+            let sample_position = (position + offset as u64) as i64;
+
+            if sample_position < self.window_start + self.pending.len() as i64 {
+                continue;
+            }
+
+            self.pending.push(sample);
+
+            if self.pending.len() == self.window.len() {
+                self.analyze_pending()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn analyze_pending(&mut self) -> anyhow::Result<()> {
+        let fft_size = self.window.len();
+        let center = self.window_start + (fft_size / 2) as i64 - self.first_sample as i64;
+
+        if center >= 0 && (center as u64) < self.samples_shown {
+            let column = (center as u64 * self.width as u64 / self.samples_shown) as usize;
+
+            let mut input = self
+                .pending
+                .iter()
+                .zip(&self.window)
+                .map(|(sample, weight)| sample * weight)
+                .collect::<Vec<f64>>();
+            let mut output = self.fft.make_output_vec();
+
+            self.fft
+                .process(&mut input, &mut output)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+            for (power, bin) in self.power[column].iter_mut().zip(&output) {
+                *power += bin.norm_sqr();
+            }
+
+            self.windows[column] += 1;
+        }
+
+        self.window_start += self.hop as i64;
+
+        let consumed = (self.hop as usize).min(self.pending.len());
+        self.pending.drain(..consumed);
+
+        Ok(())
+    }
+
+    /// Pads the last windows with silence and returns the level of every bin of every column
+    /// in dB relative to full scale.
+    fn finish(mut self) -> anyhow::Result<Vec<Vec<f64>>> {
+        let fft_size = self.window.len();
+
+        while (self.window_start + (fft_size / 2) as i64)
+            < (self.first_sample + self.samples_shown) as i64
+        {
+            self.pending.resize(fft_size, 0.0);
+            self.analyze_pending()?;
+        }
+
+        // A full scale sine reaches 0dB
+        let normalization = 4.0 / (self.window_gain * self.window_gain);
+        let mut columns: Vec<Vec<f64>> = vec![];
+
+        for (power, windows) in self.power.iter().zip(&self.windows) {
+            let column = match windows {
+                0 => columns
+                    .last()
+                    .cloned()
+                    .unwrap_or_else(|| vec![-DYNAMIC_RANGE; power.len()]),
+                windows => power
+                    .iter()
+                    .map(|power| 10.0 * (power * normalization / *windows as f64 + 1e-30).log10())
+                    .collect(),
+            };
+
+            columns.push(column);
+        }
+
+        Ok(columns)
+    }
+}
+
+fn kaiser_window(size: usize) -> Vec<f64> {
+    let denominator = bessel_i0(KAISER_BETA);
+
+    (0..size)
+        .map(|i| {
+            let ratio = 2.0 * i as f64 / (size - 1) as f64 - 1.0;
+            bessel_i0(KAISER_BETA * (1.0 - ratio * ratio).sqrt()) / denominator
+        })
+        .collect()
+}
+
+/// The modified Bessel function of the first kind and order zero, as a power series.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+
+    sum
+}
+
+fn color_of(level: f64) -> [u8; 3] {
+    let position = ((level + DYNAMIC_RANGE) / DYNAMIC_RANGE).clamp(0.0, 1.0);
+
+    for stops in PALETTE.windows(2) {
+        let ((from, from_color), (to, to_color)) = (stops[0], stops[1]);
+
+        if position <= to {
+            let t = (position - from) / (to - from);
+
+            return [0, 1, 2].map(|channel| {
+                (from_color[channel] as f64
+                    + t * (to_color[channel] as f64 - from_color[channel] as f64))
+                    .round() as u8
+            });
+        }
+    }
+
+    PALETTE[PALETTE.len() - 1].1
+}
+
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: BACKGROUND.repeat(width * height),
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        if x < self.width && y < self.height {
+            let offset = (y * self.width + x) * 3;
+            self.pixels[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+
+    fn text_width(text: &str) -> usize {
+        text.chars().count() * (GLYPH_WIDTH + 1) * GLYPH_SCALE
+    }
+
+    fn draw_text(&mut self, x: usize, y: usize, text: &str) {
+        for (index, c) in text.chars().enumerate() {
+            let glyph_x = x + index * (GLYPH_WIDTH + 1) * GLYPH_SCALE;
+
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                        continue;
+                    }
+
+                    for dy in 0..GLYPH_SCALE {
+                        for dx in 0..GLYPH_SCALE {
+                            self.set(
+                                glyph_x + column * GLYPH_SCALE + dx,
+                                y + row * GLYPH_SCALE + dy,
+                                LABEL_COLOR,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn write_png(
+    output_path: &Path,
+    title: &str,
+    columns: &[Vec<f64>],
+    height: usize,
+    sample_rate: u32,
+    time_range: (f64, f64),
+) -> anyhow::Result<()> {
+    let width = columns.len();
+    let mut canvas = Canvas::new(
+        LEFT_MARGIN + width + RIGHT_MARGIN,
+        TOP_MARGIN + height + BOTTOM_MARGIN,
+    );
+
+    for (x, column) in columns.iter().enumerate() {
+        for (bin, level) in column.iter().enumerate() {
+            canvas.set(
+                LEFT_MARGIN + x,
+                TOP_MARGIN + height - 1 - bin,
+                color_of(*level),
+            );
+        }
+    }
+
+    let nyquist = sample_rate / 2;
+    let step = GRIDLINE_STEPS
+        .iter()
+        .copied()
+        .find(|step| nyquist / step <= MAX_GRIDLINES)
+        .unwrap_or(GRIDLINE_STEPS[GRIDLINE_STEPS.len() - 1]);
+
+    let glyph_height = GLYPH_HEIGHT * GLYPH_SCALE;
+
+    for frequency in (step..=nyquist).step_by(step as usize) {
+        let y = TOP_MARGIN + height
+            - 1
+            - (frequency as f64 / nyquist as f64 * (height - 1) as f64).round() as usize;
+
+        for x in LEFT_MARGIN..LEFT_MARGIN + width {
+            // Dotted, so the spectrum below stays visible
+            if x % 4 == 0 {
+                canvas.set(x, y, GRID_COLOR);
+            }
+        }
+
+        let label = format!("{}kHz", frequency / 1000);
+        let label_x = LEFT_MARGIN.saturating_sub(Canvas::text_width(&label) + 2);
+        canvas.draw_text(label_x, y.saturating_sub(glyph_height / 2), &label);
+    }
+
+    draw_title(&mut canvas, title);
+
+    let (start, end) = time_range;
+    let show_tenths = end - start < TIME_LABELS as f64 * 2.0;
+
+    for index in 0..TIME_LABELS {
+        let fraction = index as f64 / (TIME_LABELS - 1) as f64;
+        let label = format_time(start + fraction * (end - start), show_tenths);
+        let label_width = Canvas::text_width(&label);
+
+        let x = (LEFT_MARGIN + (fraction * (width - 1) as f64) as usize)
+            .saturating_sub(label_width / 2)
+            .min(canvas.width - label_width);
+
+        canvas.draw_text(x, TOP_MARGIN + height + 6, &label);
+    }
+
+    let file = BufWriter::new(File::create(output_path)?);
+    let mut encoder = png::Encoder::new(file, canvas.width as u32, canvas.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&canvas.pixels)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Centers the title in the top margin, titles wider than the image are cut off at the end.
+fn draw_title(canvas: &mut Canvas, title: &str) {
+    let glyph_advance = (GLYPH_WIDTH + 1) * GLYPH_SCALE;
+    let max_chars = canvas.width.saturating_sub(2 * RIGHT_MARGIN) / glyph_advance;
+
+    let title = title
+        .chars()
+        .take(max_chars)
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+
+    let x = (canvas.width - Canvas::text_width(&title)) / 2;
+    let y = (TOP_MARGIN - GLYPH_HEIGHT * GLYPH_SCALE) / 2;
+
+    canvas.draw_text(x, y, &title);
+}
+
+fn format_time(seconds: f64, show_tenths: bool) -> String {
+    let minutes = (seconds / 60.0).floor();
+    let seconds = seconds - minutes * 60.0;
+
+    if show_tenths {
+        format!("{}:{:04.1}", minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds.floor())
+    }
+}
+
+/// The characters of the labels and of file names, titles are drawn in upper case.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'k' => [0b100, 0b101, 0b110, 0b101, 0b101],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'z' => [0b000, 0b111, 0b011, 0b110, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+        ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+        _ => [0b000; GLYPH_HEIGHT],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bessel_i0_matches_known_values() {
+        assert_eq!(bessel_i0(0.0), 1.0);
+        assert!((bessel_i0(1.0) - 1.2660658777520082).abs() < 1e-9);
+        assert!((bessel_i0(5.0) - 27.239871823604442).abs() < 1e-7);
+    }
+
+    #[test]
+    fn kaiser_window_is_symmetric_and_peaks_at_one() {
+        let window = kaiser_window(9);
+
+        assert!((window[4] - 1.0).abs() < 1e-12);
+        assert!((window[0] - window[8]).abs() < 1e-12);
+        assert!(window[0] < window[1]);
+    }
+
+    #[test]
+    fn colors_follow_palette() {
+        assert_eq!(color_of(-DYNAMIC_RANGE), [0, 0, 0]);
+        assert_eq!(color_of(0.0), [255, 255, 255]);
+        // Halfway between the stops at 0.35 and 0.55
+        assert_eq!(color_of(-66.0), [176, 0, 112]);
+    }
+
+    #[test]
+    fn colors_clamp_outside_dynamic_range() {
+        assert_eq!(color_of(-200.0), color_of(-DYNAMIC_RANGE));
+        assert_eq!(color_of(6.0), color_of(0.0));
+    }
+
+    #[test]
+    fn formats_minutes_and_seconds() {
+        assert_eq!(format_time(0.0, false), "0:00");
+        assert_eq!(format_time(65.9, false), "1:05");
+        assert_eq!(format_time(3725.0, false), "62:05");
+    }
+
+    #[test]
+    fn formats_tenths_for_short_spans() {
+        assert_eq!(format_time(5.25, true), "0:05.2");
+        assert_eq!(format_time(61.5, true), "1:01.5");
+    }
+}
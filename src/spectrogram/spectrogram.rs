@@ -1,99 +1,74 @@
-use crate::ext_deps::util::get_sox_executable;
-use std::path::PathBuf;
-use tokio::process::Command;
+use crate::spectrogram::render::{render_spectrogram, Span};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Two seconds from the first minute on, at a high frequency resolution.
+const ZOOM_START: f64 = 60.0;
+const ZOOM_DURATION: f64 = 2.0;
 
 pub async fn make_spectrogram_zoom(
-    folder_path: &PathBuf,
-    file_path: &PathBuf,
-    output_dir: &PathBuf,
+    folder_path: &Path,
+    file_path: &Path,
+    output_dir: &Path,
 ) -> anyhow::Result<()> {
-    let folder_name = folder_path.file_name().unwrap().to_str().unwrap();
-    let filename = file_path.file_name().unwrap().to_str().unwrap();
+    let output_path =
+        get_spectrogram_output_path(folder_path, file_path, output_dir, ".spectrogram-zoom.png")
+            .await?;
 
-    let filename_new = filename.replace(".flac", ".spectrogram-zoom.png");
+    let span = Span::Zoom {
+        start: ZOOM_START,
+        duration: ZOOM_DURATION,
+    };
 
-    let mut cmd = Command::new(get_sox_executable());
-    cmd.arg(file_path.to_str().unwrap());
-    cmd.arg("-n");
-    cmd.arg("remix");
-    cmd.arg("1");
-    cmd.arg("spectrogram");
-    cmd.arg("-x");
-    cmd.arg("500");
-    cmd.arg("-y");
-    cmd.arg("1025");
-    cmd.arg("-z");
-    cmd.arg("120");
-    cmd.arg("-w");
-    cmd.arg("Kaiser");
-    cmd.arg("-S");
-    cmd.arg("1:00");
-    cmd.arg("-d");
-    cmd.arg("0:02");
-    cmd.arg("-t");
-    cmd.arg(&filename);
-    cmd.arg("-c");
-    cmd.arg("red_oxide");
-    cmd.arg("-o");
-    cmd.arg(
-        output_dir
-            .join(folder_name)
-            .join(filename_new)
-            .to_str()
-            .unwrap(),
-    );
+    render(file_path, output_path, span, 500, 1025).await
+}
 
-    let output = cmd.output().await?;
+pub async fn make_spectrogram_full(
+    folder_path: &Path,
+    file_path: &Path,
+    output_dir: &Path,
+) -> anyhow::Result<()> {
+    let output_path =
+        get_spectrogram_output_path(folder_path, file_path, output_dir, ".spectrogram-full.png")
+            .await?;
 
-    if output.status.success() {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("Failed to create spectrogram"))
-    }
+    render(file_path, output_path, Span::Full, 3000, 513).await
 }
 
-pub async fn make_spectrogram_full(
-    folder_path: &PathBuf,
-    file_path: &PathBuf,
-    output_dir: &PathBuf,
+async fn render(
+    file_path: &Path,
+    output_path: PathBuf,
+    span: Span,
+    width: usize,
+    height: usize,
 ) -> anyhow::Result<()> {
+    let file_path = file_path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        render_spectrogram(&file_path, &output_path, span, width, height)
+            .map_err(|e| anyhow::anyhow!("Failed to create spectrogram: {}", e))
+    })
+    .await?
+}
+
+async fn get_spectrogram_output_path(
+    folder_path: &Path,
+    file_path: &Path,
+    output_dir: &Path,
+    suffix: &str,
+) -> anyhow::Result<PathBuf> {
     let folder_name = folder_path.file_name().unwrap().to_str().unwrap();
     let filename = file_path.file_name().unwrap().to_str().unwrap();
 
-    let filename_new = filename.replace(".flac", ".spectrogram-full.png");
+    // Mirror the disc subfolders (e.g. CD1/CD2) of the release so per-disc files do not collide
+    let relative_dir = file_path.strip_prefix(folder_path)?.parent().unwrap();
 
-    let mut cmd = Command::new(get_sox_executable());
-    cmd.arg(file_path.to_str().unwrap());
-    cmd.arg("-n");
-    cmd.arg("remix");
-    cmd.arg("1");
-    cmd.arg("spectrogram");
-    cmd.arg("-x");
-    cmd.arg("3000");
-    cmd.arg("-y");
-    cmd.arg("513");
-    cmd.arg("-z");
-    cmd.arg("120");
-    cmd.arg("-w");
-    cmd.arg("Kaiser");
-    cmd.arg("-t");
-    cmd.arg(&filename);
-    cmd.arg("-c");
-    cmd.arg("red_oxide");
-    cmd.arg("-o");
-    cmd.arg(
-        output_dir
-            .join(folder_name)
-            .join(filename_new)
-            .to_str()
-            .unwrap(),
-    );
+    let output_path = output_dir
+        .join(folder_name)
+        .join(relative_dir)
+        .join(filename.replace(".flac", suffix));
 
-    let output = cmd.output().await?;
+    fs::create_dir_all(output_path.parent().unwrap()).await?;
 
-    if output.status.success() {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("Failed to create spectrogram"))
-    }
+    Ok(output_path)
 }
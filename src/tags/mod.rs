@@ -1 +1,3 @@
+pub mod opus;
+pub mod replaygain;
 pub mod util;
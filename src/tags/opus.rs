@@ -0,0 +1,256 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+const OPUS_HEAD_MAGIC: &[u8] = b"OpusHead";
+const OPUS_TAGS_MAGIC: &[u8] = b"OpusTags";
+
+/// Reads the comments (e.g. `ARTIST=...`) of an Ogg Opus file as key value pairs.
+pub fn read_opus_comments(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let data = std::fs::read(path)?;
+    let mut reader = PacketReader::new(Cursor::new(data));
+
+    // The comment header is always the second packet of the stream
+    reader.read_packet_expected()?;
+    let tags_packet = reader.read_packet_expected()?;
+
+    let (_, comments) = parse_opus_tags(&tags_packet.data)
+        .ok_or_else(|| anyhow::anyhow!("No OpusTags header found in \"{}\"", path.display()))?;
+
+    Ok(comments)
+}
+
+/// Replaces the comments of an Ogg Opus file, the audio packets and their granule positions are
+/// copied unchanged.
+pub fn write_opus_comments(path: &Path, comments: &[(String, String)]) -> anyhow::Result<()> {
+    let data = std::fs::read(path)?;
+    let mut reader = PacketReader::new(Cursor::new(data));
+    let mut writer = PacketWriter::new(Vec::new());
+
+    let mut index = 0;
+
+    while let Some(packet) = reader.read_packet()? {
+        let end_info = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+
+        let serial = packet.stream_serial();
+        let absgp = packet.absgp_page();
+
+        let content = match index {
+            0 if !packet.data.starts_with(OPUS_HEAD_MAGIC) => {
+                return Err(anyhow::anyhow!(
+                    "\"{}\" is not an Ogg Opus file",
+                    path.display()
+                ));
+            }
+            1 => {
+                let (vendor, _) = parse_opus_tags(&packet.data).ok_or_else(|| {
+                    anyhow::anyhow!("No OpusTags header found in \"{}\"", path.display())
+                })?;
+
+                // The comment header has to end its page so the audio starts on a fresh one
+                let content = build_opus_tags(&vendor, comments);
+                writer.write_packet(
+                    content.into_boxed_slice(),
+                    serial,
+                    PacketWriteEndInfo::EndPage,
+                    absgp,
+                )?;
+
+                index += 1;
+                continue;
+            }
+            _ => packet.data,
+        };
+
+        writer.write_packet(content.into_boxed_slice(), serial, end_info, absgp)?;
+        index += 1;
+    }
+
+    std::fs::write(path, writer.into_inner())?;
+
+    Ok(())
+}
+
+/// Returns the pre-skip from the identification header and the granule position of the last
+/// page, together they give the exact length of the decoded audio at 48kHz.
+pub fn read_opus_length(path: &Path) -> anyhow::Result<u64> {
+    let data = std::fs::read(path)?;
+    let mut reader = PacketReader::new(Cursor::new(data));
+
+    let head = reader.read_packet_expected()?;
+
+    if !head.data.starts_with(OPUS_HEAD_MAGIC) || head.data.len() < 12 {
+        return Err(anyhow::anyhow!(
+            "\"{}\" is not an Ogg Opus file",
+            path.display()
+        ));
+    }
+
+    let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+
+    let mut last_granule = 0;
+
+    while let Some(packet) = reader.read_packet()? {
+        last_granule = packet.absgp_page();
+    }
+
+    Ok(last_granule.saturating_sub(pre_skip))
+}
+
+fn parse_opus_tags(data: &[u8]) -> Option<(String, Vec<(String, String)>)> {
+    if !data.starts_with(OPUS_TAGS_MAGIC) {
+        return None;
+    }
+
+    let mut offset = OPUS_TAGS_MAGIC.len();
+
+    let read_u32 = |offset: &mut usize| -> Option<usize> {
+        let value = u32::from_le_bytes(data.get(*offset..*offset + 4)?.try_into().ok()?);
+        *offset += 4;
+        Some(value as usize)
+    };
+
+    let vendor_length = read_u32(&mut offset)?;
+    let vendor = String::from_utf8_lossy(data.get(offset..offset + vendor_length)?).to_string();
+    offset += vendor_length;
+
+    let count = read_u32(&mut offset)?;
+    let mut comments = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let length = read_u32(&mut offset)?;
+        let comment = String::from_utf8_lossy(data.get(offset..offset + length)?).to_string();
+        offset += length;
+
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    Some((vendor, comments))
+}
+
+fn build_opus_tags(vendor: &str, comments: &[(String, String)]) -> Vec<u8> {
+    let mut data = OPUS_TAGS_MAGIC.to_vec();
+
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor.as_bytes());
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+
+    for (key, value) in comments {
+        let comment = format!("{}={}", key, value);
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opus_head(pre_skip: u16) -> Vec<u8> {
+        let mut head = OPUS_HEAD_MAGIC.to_vec();
+        head.push(1);
+        head.push(2);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&48000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        head
+    }
+
+    /// Writes a minimal Ogg Opus stream: the two headers and two audio packets on their own pages.
+    fn write_test_file(path: &Path, comments: &[(String, String)]) {
+        let mut writer = PacketWriter::new(Vec::new());
+        let serial = 0x1234;
+
+        writer
+            .write_packet(
+                opus_head(312).into_boxed_slice(),
+                serial,
+                PacketWriteEndInfo::EndPage,
+                0,
+            )
+            .unwrap();
+        writer
+            .write_packet(
+                build_opus_tags("libopus 1.4", comments).into_boxed_slice(),
+                serial,
+                PacketWriteEndInfo::EndPage,
+                0,
+            )
+            .unwrap();
+        writer
+            .write_packet(
+                Box::new([0xfc, 0x01]),
+                serial,
+                PacketWriteEndInfo::EndPage,
+                960,
+            )
+            .unwrap();
+        writer
+            .write_packet(
+                Box::new([0xfc, 0x02]),
+                serial,
+                PacketWriteEndInfo::EndStream,
+                48312,
+            )
+            .unwrap();
+
+        std::fs::write(path, writer.into_inner()).unwrap();
+    }
+
+    fn comment(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn builds_and_parses_tags() {
+        let comments = vec![comment("ARTIST", "Someone"), comment("TITLE", "a=b")];
+
+        let (vendor, parsed) = parse_opus_tags(&build_opus_tags("vendor", &comments)).unwrap();
+
+        assert_eq!(vendor, "vendor");
+        assert_eq!(parsed, comments);
+    }
+
+    #[test]
+    fn rejects_truncated_tags() {
+        let data = build_opus_tags("vendor", &[comment("ARTIST", "Someone")]);
+
+        assert_eq!(parse_opus_tags(&data[..data.len() - 3]), None);
+        assert_eq!(parse_opus_tags(b"OpusHead"), None);
+    }
+
+    #[test]
+    fn rewrites_comments_and_keeps_audio() {
+        let path = std::env::temp_dir().join(format!(
+            "red_oxide-test-{}-opus-comments.opus",
+            std::process::id()
+        ));
+
+        write_test_file(&path, &[comment("ENCODER", "opusenc")]);
+
+        let comments = vec![
+            comment("ARTIST", "Somebody with a longer name than before"),
+            comment("TRACKNUMBER", "1"),
+        ];
+        write_opus_comments(&path, &comments).unwrap();
+
+        let read_back = read_opus_comments(&path).unwrap();
+        let length = read_opus_length(&path).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read_back, comments);
+        assert_eq!(length, 48000);
+    }
+}
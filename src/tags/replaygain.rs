@@ -0,0 +1,77 @@
+use std::path::Path;
+
+use id3::frame::ExtendedText;
+use id3::TagLike;
+
+/// Gains in dB relative to the ReplayGain 2.0 reference of -18 LUFS, peaks as linear sample
+/// values where 1.0 is full scale.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayGainValues {
+    pub track_gain: f64,
+    pub track_peak: f64,
+    pub album_gain: f64,
+    pub album_peak: f64,
+}
+
+impl ReplayGainValues {
+    fn tags(&self) -> [(&'static str, String); 4] {
+        [
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                format!("{:.2} dB", self.track_gain),
+            ),
+            ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.track_peak)),
+            (
+                "REPLAYGAIN_ALBUM_GAIN",
+                format!("{:.2} dB", self.album_gain),
+            ),
+            ("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", self.album_peak)),
+        ]
+    }
+}
+
+/// Writes the `REPLAYGAIN_*` tags, as Vorbis comments into FLACs and as TXXX frames into MP3s,
+/// replacing values of an earlier run.
+pub async fn write_replaygain_tags(path: &Path, values: ReplayGainValues) -> anyhow::Result<()> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let extension = path
+            .extension()
+            .map(|e| e.to_str().unwrap().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "flac" => {
+                let mut tag = metaflac::Tag::read_from_path(&path)?;
+
+                for (key, value) in values.tags() {
+                    tag.set_vorbis(key, vec![value]);
+                }
+
+                tag.save()?;
+            }
+            "mp3" => {
+                let mut tag = id3::Tag::read_from_path(&path)?;
+
+                for (key, value) in values.tags() {
+                    tag.add_frame(ExtendedText {
+                        description: key.to_string(),
+                        value,
+                    });
+                }
+
+                tag.write_to_path(&path, id3::Version::Id3v24)?;
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Can not write ReplayGain tags to \"{}\", unsupported file type",
+                    path.display()
+                ))
+            }
+        }
+
+        Ok(())
+    })
+    .await?
+}
@@ -1,11 +1,17 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::redacted::models::Media;
 use crate::redacted::models::Media::Vinyl;
+use crate::tags::opus::{read_opus_comments, write_opus_comments};
 use async_recursion::async_recursion;
 use audiotags::{Tag, TagType};
+use metaflac::BlockType;
 use tokio::fs;
 
+/// Vorbis comment keys rippers and taggers use to flag pre-emphasis. Transcodes are always
+/// de-emphasized, so these are never copied to them.
+pub const EMPHASIS_TAG_KEYS: [&str; 3] = ["PRE_EMPHASIS", "PREEMPHASIS", "EMPHASIS"];
+
 pub async fn copy_tags_to_mp3(from: &PathBuf, to: &PathBuf) -> anyhow::Result<()> {
     let from_tag = Tag::default().read_from_path(from)?;
 
@@ -15,6 +21,90 @@ pub async fn copy_tags_to_mp3(from: &PathBuf, to: &PathBuf) -> anyhow::Result<()
     return Ok(());
 }
 
+pub async fn copy_tags_to_m4a(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let from_tag = Tag::default().read_from_path(from)?;
+
+    let mut m4a_tags = from_tag.to_dyn_tag(TagType::Mp4);
+    m4a_tags.write_to_path(to.to_str().unwrap())?;
+
+    Ok(())
+}
+
+pub async fn copy_tags_to_opus(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let from_tag = metaflac::Tag::read_from_path(from)?;
+
+    // Opus uses the same Vorbis comments as FLAC, so they are copied as they are
+    let mut comments = vec![];
+
+    if let Some(vorbis_comments) = from_tag.vorbis_comments() {
+        for (key, values) in &vorbis_comments.comments {
+            if is_emphasis_tag(key) {
+                continue;
+            }
+
+            for value in values {
+                comments.push((key.clone(), value.clone()));
+            }
+        }
+    }
+
+    comments.sort();
+
+    let to = to.to_path_buf();
+    tokio::task::spawn_blocking(move || write_opus_comments(&to, &comments)).await??;
+
+    Ok(())
+}
+
+pub async fn copy_tags_to_flac(from: &PathBuf, to: &PathBuf) -> anyhow::Result<()> {
+    let from_tag = metaflac::Tag::read_from_path(from)?;
+    let mut flac_tags = metaflac::Tag::read_from_path(to)?;
+
+    flac_tags.remove_blocks(BlockType::VorbisComment);
+    flac_tags.remove_blocks(BlockType::Picture);
+
+    for block_type in [BlockType::VorbisComment, BlockType::Picture] {
+        for block in from_tag.get_blocks(block_type) {
+            flac_tags.push_block(block.clone());
+        }
+    }
+
+    if flac_tags.vorbis_comments().is_some() {
+        flac_tags
+            .vorbis_comments_mut()
+            .comments
+            .retain(|key, _| !is_emphasis_tag(key));
+    }
+
+    flac_tags.save()?;
+
+    Ok(())
+}
+
+fn is_emphasis_tag(key: &str) -> bool {
+    EMPHASIS_TAG_KEYS
+        .iter()
+        .any(|emphasis_key| key.eq_ignore_ascii_case(emphasis_key))
+}
+
+pub fn has_basic_tags(path: &Path) -> bool {
+    if path.extension().is_some_and(|e| e == "opus") {
+        return match read_opus_comments(path) {
+            Ok(comments) => ["ARTIST", "ALBUM", "TITLE"].iter().all(|wanted| {
+                comments
+                    .iter()
+                    .any(|(key, _)| key.eq_ignore_ascii_case(wanted))
+            }),
+            Err(_) => false,
+        };
+    }
+
+    match Tag::new().read_from_path(path) {
+        Ok(tag) => tag.artist().is_some() && tag.album().is_some() && tag.title().is_some(),
+        Err(_) => false,
+    }
+}
+
 #[async_recursion]
 pub async fn valid_tags(flac_dir_path: &PathBuf, media: &Media) -> anyhow::Result<(bool, bool)> {
     let mut dir = fs::read_dir(flac_dir_path).await?;
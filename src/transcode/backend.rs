@@ -0,0 +1,835 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::config::models::EncoderProfile;
+use crate::ext_deps::util::{
+    get_fdkaac_executable, get_ffmpeg_executable, get_flac_executable, get_lame_executable,
+    get_opusenc_executable, get_sox_executable,
+};
+use crate::redacted::models::ReleaseType;
+use crate::redacted::models::ReleaseType::{Aac256, Flac, Flac24, Mp3320, Mp3V0, Mp3V2, Opus};
+use crate::transcode::native::{DecodeSettings, PcmFormat};
+
+/// Stands in for the input file in arguments until the real path is filled in.
+pub const INPUT_PLACEHOLDER: &str = "{input}";
+
+/// Stands in for the output file in arguments until the real path is filled in.
+pub const OUTPUT_PLACEHOLDER: &str = "{output}";
+
+/// Stands in for the target sample rate in profile templates.
+pub const RATE_PLACEHOLDER: &str = "{rate}";
+
+/// Stands in for the dither arguments of a profile in its resampler template.
+pub const DITHER_PLACEHOLDER: &str = "{dither}";
+
+/// Dither arguments used by profiles which do not configure their own.
+const DEFAULT_PROFILE_DITHER: &str = "dither";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+pub enum EncoderBackendType {
+    Tools,
+    Ffmpeg,
+}
+
+impl EncoderBackendType {
+    pub fn backend(&self) -> Arc<dyn EncoderBackend> {
+        match self {
+            EncoderBackendType::Tools => Arc::new(ToolsBackend),
+            EncoderBackendType::Ffmpeg => Arc::new(FfmpegBackend),
+        }
+    }
+}
+
+/// The source properties a decoder is built for.
+pub struct SourceInfo {
+    pub sample_rate: u32,
+    pub bits_per_sample: u32,
+    pub channels: u32,
+}
+
+pub struct ExternalDecoder {
+    pub step: &'static str,
+    pub command: Command,
+    pub command_str: String,
+    /// Layout of the raw PCM the decoder writes, `None` if it writes WAV
+    pub pcm_format: Option<PcmFormat>,
+}
+
+/// Owns how the audio of a FLAC is decoded by external programs, how every format is encoded
+/// and how the process is rendered for the description.
+pub trait EncoderBackend: Send + Sync {
+    /// The decoder used when the built-in decoder is turned off, resampling, mixing down and
+    /// de-emphasizing the source as the settings ask for.
+    fn decoder(
+        &self,
+        input: &Path,
+        source: &SourceInfo,
+        settings: &DecodeSettings,
+    ) -> ExternalDecoder;
+
+    /// The encoder producing the given format from audio on stdin, which is raw PCM of the
+    /// given layout or WAV if there is none.
+    fn encoder(
+        &self,
+        format: ReleaseType,
+        output: &Path,
+        pcm_format: Option<&PcmFormat>,
+    ) -> (Command, String);
+
+    /// If MP3s of the given format carry a LAME header that proves their encoder settings.
+    fn writes_lame_header(&self, format: ReleaseType) -> bool;
+
+    /// If the built-in decoder can feed this backend, otherwise its own decoder is always used.
+    fn accepts_native_decoder(&self) -> bool {
+        true
+    }
+
+    /// If the decoder of this backend can undo pre-emphasis.
+    fn supports_deemphasis(&self) -> bool {
+        true
+    }
+
+    /// Renders the whole process of a track for the description.
+    fn render_process(&self, decoder_str: &str, encoder_str: &str) -> String {
+        format!("{} | {}", decoder_str, encoder_str)
+    }
+}
+
+/// The classic pipeline of sox (resampling) or flac (decoding) feeding flac and lame.
+pub struct ToolsBackend;
+
+impl EncoderBackend for ToolsBackend {
+    fn decoder(
+        &self,
+        input: &Path,
+        source: &SourceInfo,
+        settings: &DecodeSettings,
+    ) -> ExternalDecoder {
+        let DecodeSettings {
+            target_sample_rate,
+            target_bits_per_sample,
+            downmix,
+            deemphasis,
+            padded_source,
+        } = settings;
+
+        match (target_sample_rate, downmix, deemphasis) {
+            (None, None, false) => {
+                let args = args(&["-dcs", "--", INPUT_PLACEHOLDER]);
+                let (command, command_str) =
+                    templated_command(get_flac_executable(), "flac", &args, Some(input), None);
+
+                ExternalDecoder {
+                    step: "decode",
+                    command,
+                    command_str,
+                    pcm_format: None,
+                }
+            }
+            _ => {
+                // Only a padded source without further processing loses nothing without dither,
+                // -D keeps sox from adding it on its own
+                let exact = *padded_source
+                    && downmix.is_none()
+                    && !deemphasis
+                    && target_sample_rate.is_none_or(|rate| rate == source.sample_rate);
+
+                let mut sox_args = args(&[INPUT_PLACEHOLDER, "-G"]);
+
+                if exact {
+                    sox_args.push("-D".to_string());
+                }
+
+                sox_args.extend(args(&[
+                    "-b",
+                    &target_bits_per_sample.to_string(),
+                    "-t",
+                    "wav",
+                    "-",
+                ]));
+
+                if *deemphasis {
+                    sox_args.push("deemph".to_string());
+                }
+
+                if let Some(downmix) = downmix {
+                    sox_args.extend(downmix.sox_remix_args());
+                }
+
+                if let Some(rate) = target_sample_rate {
+                    sox_args.extend(args(&["rate", "-v", "-L", &rate.to_string()]));
+                }
+
+                if !exact {
+                    sox_args.push("dither".to_string());
+                }
+
+                let (command, command_str) =
+                    templated_command(get_sox_executable(), "sox", &sox_args, Some(input), None);
+
+                ExternalDecoder {
+                    step: match (target_sample_rate, downmix) {
+                        (Some(_), _) => "resample",
+                        (None, Some(_)) => "downmix",
+                        (None, None) => "de-emphasis",
+                    },
+                    command,
+                    command_str,
+                    pcm_format: None,
+                }
+            }
+        }
+    }
+
+    fn encoder(
+        &self,
+        format: ReleaseType,
+        output: &Path,
+        pcm_format: Option<&PcmFormat>,
+    ) -> (Command, String) {
+        match format {
+            Mp3V0 | Mp3320 | Mp3V2 => {
+                let mut encoder_args = vec![];
+
+                if let Some(pcm_format) = pcm_format {
+                    encoder_args.extend(args(&[
+                        "-r",
+                        "-s",
+                        &(pcm_format.sample_rate as f64 / 1000.0).to_string(),
+                        "--bitwidth",
+                        &pcm_format.bits_per_sample.to_string(),
+                        "--signed",
+                        "--little-endian",
+                    ]));
+
+                    if pcm_format.channels == 1 {
+                        encoder_args.extend(args(&["-m", "m"]));
+                    }
+                }
+
+                let settings: &[&str] = match format {
+                    Mp3V0 => &["-S", "-V", "0", "--vbr-new", "--ignore-tag-errors"],
+                    Mp3V2 => &["-S", "-V", "2", "--vbr-new", "--ignore-tag-errors"],
+                    _ => &["-S", "-h", "-b", "320", "--ignore-tag-errors"],
+                };
+
+                encoder_args.extend(args(settings));
+                encoder_args.extend(args(&["-", OUTPUT_PLACEHOLDER]));
+
+                templated_command(
+                    get_lame_executable(),
+                    "lame",
+                    &encoder_args,
+                    None,
+                    Some((output, "output.mp3")),
+                )
+            }
+            Flac | Flac24 => {
+                let mut encoder_args = args(&["--best"]);
+
+                if let Some(pcm_format) = pcm_format {
+                    encoder_args.extend(args(&[
+                        "--force-raw-format",
+                        "--endian=little",
+                        "--sign=signed",
+                        &format!("--channels={}", pcm_format.channels),
+                        &format!("--bps={}", pcm_format.bits_per_sample),
+                        &format!("--sample-rate={}", pcm_format.sample_rate),
+                    ]));
+                }
+
+                encoder_args.extend(args(&["-o", OUTPUT_PLACEHOLDER, "-"]));
+
+                templated_command(
+                    get_flac_executable(),
+                    "flac",
+                    &encoder_args,
+                    None,
+                    Some((output, "output.flac")),
+                )
+            }
+            Aac256 => {
+                let mut encoder_args = vec![];
+
+                if let Some(pcm_format) = pcm_format {
+                    encoder_args.extend(args(&[
+                        "-R",
+                        "--raw-channels",
+                        &pcm_format.channels.to_string(),
+                        "--raw-rate",
+                        &pcm_format.sample_rate.to_string(),
+                        "--raw-format",
+                        &format!("S{}L", pcm_format.bits_per_sample),
+                    ]));
+                }
+
+                encoder_args.extend(args(&[
+                    "-S",
+                    "-m",
+                    "0",
+                    "-b",
+                    "256",
+                    "-o",
+                    OUTPUT_PLACEHOLDER,
+                    "-",
+                ]));
+
+                templated_command(
+                    get_fdkaac_executable(),
+                    "fdkaac",
+                    &encoder_args,
+                    None,
+                    Some((output, output_name(format))),
+                )
+            }
+            Opus => {
+                let mut encoder_args = vec![];
+
+                if let Some(pcm_format) = pcm_format {
+                    encoder_args.extend(args(&[
+                        "--raw",
+                        "--raw-bits",
+                        &pcm_format.bits_per_sample.to_string(),
+                        "--raw-rate",
+                        &pcm_format.sample_rate.to_string(),
+                        "--raw-chan",
+                        &pcm_format.channels.to_string(),
+                        "--raw-endianness",
+                        "0",
+                    ]));
+                }
+
+                encoder_args.extend(args(&[
+                    "--quiet",
+                    "--vbr",
+                    "--bitrate",
+                    "192",
+                    "-",
+                    OUTPUT_PLACEHOLDER,
+                ]));
+
+                templated_command(
+                    get_opusenc_executable(),
+                    "opusenc",
+                    &encoder_args,
+                    None,
+                    Some((output, output_name(format))),
+                )
+            }
+        }
+    }
+
+    fn writes_lame_header(&self, _format: ReleaseType) -> bool {
+        true
+    }
+}
+
+/// Decodes, resamples and encodes everything with ffmpeg, for systems where it is the only
+/// packaged tool.
+pub struct FfmpegBackend;
+
+impl EncoderBackend for FfmpegBackend {
+    fn decoder(
+        &self,
+        input: &Path,
+        source: &SourceInfo,
+        settings: &DecodeSettings,
+    ) -> ExternalDecoder {
+        let DecodeSettings {
+            target_sample_rate,
+            target_bits_per_sample,
+            downmix,
+            deemphasis,
+            padded_source,
+        } = settings;
+
+        let sample_rate = target_sample_rate.unwrap_or(source.sample_rate);
+
+        let exact = *padded_source
+            && downmix.is_none()
+            && !deemphasis
+            && sample_rate == source.sample_rate;
+
+        // 24 bit PCM is produced from 32 bit samples, there is nothing left to dither at that depth
+        let sample_format = match target_bits_per_sample {
+            24 => "s32".to_string(),
+            _ if exact => "s16".to_string(),
+            _ => "s16:dither_method=triangular".to_string(),
+        };
+
+        let mut filters = vec![];
+
+        if *deemphasis {
+            filters.push("aemphasis=mode=reproduction:type=cd".to_string());
+        }
+
+        if let Some(downmix) = downmix {
+            filters.push(downmix.ffmpeg_pan_filter());
+        }
+
+        filters.push(format!(
+            "aresample=resampler=soxr:precision=28:osr={}:osf={}",
+            sample_rate, sample_format
+        ));
+
+        let decoder_args = args(&[
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-i",
+            INPUT_PLACEHOLDER,
+            "-af",
+            &filters.join(","),
+            "-f",
+            &format!("s{}le", target_bits_per_sample),
+            "-",
+        ]);
+
+        let (command, command_str) = templated_command(
+            get_ffmpeg_executable(),
+            "ffmpeg",
+            &decoder_args,
+            Some(input),
+            None,
+        );
+
+        ExternalDecoder {
+            step: if target_sample_rate.is_some() {
+                "resample"
+            } else {
+                "decode"
+            },
+            command,
+            command_str,
+            pcm_format: Some(PcmFormat {
+                sample_rate,
+                channels: if downmix.is_some() {
+                    2
+                } else {
+                    source.channels
+                },
+                bits_per_sample: *target_bits_per_sample,
+            }),
+        }
+    }
+
+    fn encoder(
+        &self,
+        format: ReleaseType,
+        output: &Path,
+        pcm_format: Option<&PcmFormat>,
+    ) -> (Command, String) {
+        let mut encoder_args = args(&["-hide_banner", "-loglevel", "error", "-y"]);
+
+        match pcm_format {
+            Some(pcm_format) => encoder_args.extend(args(&[
+                "-f",
+                &format!("s{}le", pcm_format.bits_per_sample),
+                "-ar",
+                &pcm_format.sample_rate.to_string(),
+                "-ac",
+                &pcm_format.channels.to_string(),
+                "-i",
+                "-",
+            ])),
+            None => encoder_args.extend(args(&["-f", "wav", "-i", "-"])),
+        }
+
+        let settings: &[&str] = match format {
+            Mp3V0 => &["-c:a", "libmp3lame", "-q:a", "0"],
+            Mp3V2 => &["-c:a", "libmp3lame", "-q:a", "2"],
+            Mp3320 => &["-c:a", "libmp3lame", "-b:a", "320k"],
+            Aac256 => &["-c:a", "aac", "-b:a", "256k"],
+            Opus => &["-c:a", "libopus", "-b:a", "192k", "-vbr", "on"],
+            Flac | Flac24 => &["-c:a", "flac", "-compression_level", "8"],
+        };
+
+        encoder_args.extend(args(settings));
+        encoder_args.push(OUTPUT_PLACEHOLDER.to_string());
+
+        templated_command(
+            get_ffmpeg_executable(),
+            "ffmpeg",
+            &encoder_args,
+            None,
+            Some((output, output_name(format))),
+        )
+    }
+
+    fn writes_lame_header(&self, _format: ReleaseType) -> bool {
+        // ffmpeg writes its own Xing header without the LAME encoder settings
+        false
+    }
+}
+
+/// Runs the command templates of a user defined encoder profile from the config file. Profile
+/// templates read and write WAV, so the profile always decodes with its own templates. Profiles
+/// can neither downmix, which is rejected when the config is verified, nor de-emphasize.
+pub struct ProfileBackend {
+    pub profile: EncoderProfile,
+}
+
+impl EncoderBackend for ProfileBackend {
+    fn decoder(
+        &self,
+        input: &Path,
+        source: &SourceInfo,
+        settings: &DecodeSettings,
+    ) -> ExternalDecoder {
+        let target_sample_rate = settings.target_sample_rate;
+
+        let (step, template) = match (target_sample_rate, &self.profile.resampler_24bit) {
+            (Some(_), Some(resampler_24bit)) if settings.target_bits_per_sample == 24 => {
+                ("resample", resampler_24bit)
+            }
+            (Some(_), _) => ("resample", &self.profile.resampler),
+            (None, _) => ("decode", &self.profile.decoder),
+        };
+
+        let rate = target_sample_rate.unwrap_or(source.sample_rate);
+
+        // A padded source which keeps its rate only drops zeros, dithering would add noise
+        let dither = if settings.padded_source && rate == source.sample_rate {
+            String::new()
+        } else {
+            self.profile
+                .dither
+                .clone()
+                .unwrap_or(DEFAULT_PROFILE_DITHER.to_string())
+        };
+        let rate = rate.to_string();
+
+        let (executable, args) = split_template(template);
+        let args = args
+            .iter()
+            .flat_map(|arg| {
+                // An empty dither setting removes the argument instead of leaving an empty one
+                if arg == DITHER_PLACEHOLDER {
+                    shell_words::split(&dither).unwrap_or_default()
+                } else {
+                    vec![arg.replace(RATE_PLACEHOLDER, &rate)]
+                }
+            })
+            .collect::<Vec<String>>();
+
+        let (command, command_str) =
+            templated_command(executable.clone(), &executable, &args, Some(input), None);
+
+        ExternalDecoder {
+            step,
+            command,
+            command_str,
+            pcm_format: None,
+        }
+    }
+
+    fn encoder(
+        &self,
+        format: ReleaseType,
+        output: &Path,
+        _pcm_format: Option<&PcmFormat>,
+    ) -> (Command, String) {
+        let (executable, args) = split_template(&self.profile.encoders[&format]);
+
+        templated_command(
+            executable.clone(),
+            &executable,
+            &args,
+            None,
+            Some((output, output_name(format))),
+        )
+    }
+
+    fn writes_lame_header(&self, format: ReleaseType) -> bool {
+        self.profile
+            .encoders
+            .get(&format)
+            .map(|template| split_template(template).0)
+            .and_then(|program| PathBuf::from(program).file_stem().map(|s| s.to_owned()))
+            .is_some_and(|program| program == "lame")
+    }
+
+    fn accepts_native_decoder(&self) -> bool {
+        false
+    }
+
+    fn supports_deemphasis(&self) -> bool {
+        false
+    }
+}
+
+/// Validates a profile so a broken template is caught at startup instead of mid-transcode.
+pub fn validate_profile(profile: &EncoderProfile, formats: &[ReleaseType]) -> Result<(), String> {
+    validate_template(
+        "decoder",
+        &profile.decoder,
+        &[INPUT_PLACEHOLDER],
+        &[INPUT_PLACEHOLDER],
+    )?;
+    validate_template(
+        "resampler",
+        &profile.resampler,
+        &[INPUT_PLACEHOLDER, RATE_PLACEHOLDER],
+        &[INPUT_PLACEHOLDER, RATE_PLACEHOLDER, DITHER_PLACEHOLDER],
+    )?;
+
+    if let Some(dither) = &profile.dither {
+        shell_words::split(dither)
+            .map_err(|e| format!("dither \"{}\" is invalid: {}", dither, e))?;
+    }
+
+    if formats.contains(&Flac24) {
+        match &profile.resampler_24bit {
+            None => return Err(format!("no resampler_24bit template for {}", Flac24)),
+            Some(template) => validate_template(
+                "resampler_24bit",
+                template,
+                &[INPUT_PLACEHOLDER, RATE_PLACEHOLDER],
+                &[INPUT_PLACEHOLDER, RATE_PLACEHOLDER, DITHER_PLACEHOLDER],
+            )?,
+        }
+    }
+
+    for format in formats {
+        match profile.encoders.get(format) {
+            None => return Err(format!("no encoder template for {}", format)),
+            Some(template) => validate_template(
+                &format!("{} encoder", format),
+                template,
+                &[OUTPUT_PLACEHOLDER],
+                &[OUTPUT_PLACEHOLDER],
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+/// A template has to contain all `required` placeholders and no other placeholders than the
+/// `allowed` ones, e.g. an encoder never gets to see the input file.
+fn validate_template(
+    name: &str,
+    template: &str,
+    required: &[&str],
+    allowed: &[&str],
+) -> Result<(), String> {
+    let parts = shell_words::split(template)
+        .map_err(|e| format!("{} template \"{}\" is invalid: {}", name, template, e))?;
+
+    if parts.is_empty() {
+        return Err(format!("{} template is empty", name));
+    }
+
+    for placeholder in required {
+        if !template.contains(placeholder) {
+            return Err(format!(
+                "{} template \"{}\" is missing {}",
+                name, template, placeholder
+            ));
+        }
+    }
+
+    for placeholder in [
+        INPUT_PLACEHOLDER,
+        OUTPUT_PLACEHOLDER,
+        RATE_PLACEHOLDER,
+        DITHER_PLACEHOLDER,
+    ] {
+        if template.contains(placeholder) && !allowed.contains(&placeholder) {
+            return Err(format!(
+                "{} template \"{}\" can not use {}",
+                name, template, placeholder
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a template into the program and its arguments like a shell would, so quoted arguments
+/// may contain spaces. Placeholders are filled in per argument so paths with spaces stay a single
+/// argument. Templates were validated on startup, so splitting them can not fail anymore.
+fn split_template(template: &str) -> (String, Vec<String>) {
+    let mut parts = shell_words::split(template).unwrap_or_default().into_iter();
+    let executable = parts.next().unwrap_or_default();
+
+    (executable, parts.collect())
+}
+
+/// The generic output file name used when rendering the commands of a format.
+fn output_name(format: ReleaseType) -> &'static str {
+    match format {
+        Mp3V0 | Mp3320 | Mp3V2 => "output.mp3",
+        Flac | Flac24 => "output.flac",
+        Aac256 => "output.m4a",
+        Opus => "output.opus",
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Builds the command to run from arguments containing the input or output placeholder, for the
+/// command they are replaced with the real path and for the rendering with a generic file name.
+/// Decoders only get an input and encoders only an output (with its generic name), a placeholder
+/// without a path is left as it is.
+fn templated_command(
+    executable: String,
+    program_name: &str,
+    args: &[String],
+    input: Option<&Path>,
+    output: Option<(&Path, &str)>,
+) -> (Command, String) {
+    let fill = |arg: &String, input_str: &str, output_str: &str| {
+        let mut arg = arg.clone();
+
+        if input.is_some() {
+            arg = arg.replace(INPUT_PLACEHOLDER, input_str);
+        }
+
+        if output.is_some() {
+            arg = arg.replace(OUTPUT_PLACEHOLDER, output_str);
+        }
+
+        arg
+    };
+
+    let input_str = input.map_or("", |input| input.to_str().unwrap());
+    let (output_str, output_name) = output.map_or(("", ""), |(output, output_name)| {
+        (output.to_str().unwrap(), output_name)
+    });
+
+    let mut command = Command::new(executable);
+    command.args(args.iter().map(|arg| fill(arg, input_str, output_str)));
+
+    let command_str = std::iter::once(program_name.to_string())
+        .chain(args.iter().map(|arg| fill(arg, "input.flac", output_name)))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    (command, command_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn profile() -> EncoderProfile {
+        EncoderProfile {
+            decoder: "flac -dcs -- {input}".to_string(),
+            resampler: "sox {input} -G -b 16 -t wav - rate -v -L {rate} {dither}".to_string(),
+            resampler_24bit: None,
+            dither: None,
+            encoders: HashMap::from([(Flac, "flac --best -o {output} -".to_string())]),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_profile() {
+        assert_eq!(validate_profile(&profile(), &[Flac]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_input_in_encoder() {
+        let mut profile = profile();
+        profile
+            .encoders
+            .insert(Flac, "flac --best -o {output} {input}".to_string());
+
+        let error = validate_profile(&profile, &[Flac]).unwrap_err();
+
+        assert!(error.contains("can not use {input}"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_output_in_decoder() {
+        let mut profile = profile();
+        profile.decoder = "flac -d -o {output} {input}".to_string();
+
+        assert!(validate_profile(&profile, &[Flac]).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_placeholder() {
+        let mut profile = profile();
+        profile.resampler = "sox {input} -t wav - rate 44100".to_string();
+
+        let error = validate_profile(&profile, &[Flac]).unwrap_err();
+
+        assert!(error.contains("missing {rate}"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unbalanced_quotes() {
+        let mut profile = profile();
+        profile.decoder = "flac -dcs \"{input}".to_string();
+
+        assert!(validate_profile(&profile, &[Flac]).is_err());
+    }
+
+    #[test]
+    fn splits_quoted_arguments() {
+        let (executable, args) =
+            split_template("\"/opt/my tools/lame\" --tc 'made with lame' - {output}");
+
+        assert_eq!(executable, "/opt/my tools/lame");
+        assert_eq!(args, vec!["--tc", "made with lame", "-", "{output}"]);
+    }
+
+    #[test]
+    fn fills_only_the_placeholder_of_the_role() {
+        let args = args(&["{input}", "{output}"]);
+
+        let (command, command_str) = templated_command(
+            "lame".to_string(),
+            "lame",
+            &args,
+            None,
+            Some((Path::new("/out/01 Track.mp3"), "output.mp3")),
+        );
+
+        let filled = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_str().unwrap().to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(filled, vec!["{input}", "/out/01 Track.mp3"]);
+        assert_eq!(command_str, "lame {input} output.mp3");
+    }
+
+    #[test]
+    fn sox_drops_padding_without_dither() {
+        let source = SourceInfo {
+            sample_rate: 44100,
+            bits_per_sample: 24,
+            channels: 2,
+        };
+        let settings = |padded_source| DecodeSettings {
+            target_sample_rate: Some(44100),
+            target_bits_per_sample: 16,
+            downmix: None,
+            deemphasis: false,
+            padded_source,
+        };
+
+        let padded = ToolsBackend.decoder(Path::new("in.flac"), &source, &settings(true));
+        let dithered = ToolsBackend.decoder(Path::new("in.flac"), &source, &settings(false));
+
+        assert_eq!(
+            padded.command_str,
+            "sox input.flac -G -D -b 16 -t wav - rate -v -L 44100"
+        );
+        assert_eq!(
+            dithered.command_str,
+            "sox input.flac -G -b 16 -t wav - rate -v -L 44100 dither"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use futures::future::join_all;
+use tokio::sync::Semaphore;
+
+use crate::fs::util::get_all_files_with_extension;
+
+/// Bit depths at or below this are CD quality, a hi-res FLAC using no more bits is padded.
+const CD_BITS_PER_SAMPLE: u32 = 16;
+
+pub struct TrackBitDepth {
+    pub bits_per_sample: u32,
+    /// How many of the high bits carry audio, `None` for a silent track
+    pub used_bits: Option<u32>,
+}
+
+/// How many bits of their declared bit depth the tracks of a release actually use.
+pub struct BitDepthReport {
+    pub tracks: Vec<TrackBitDepth>,
+}
+
+impl BitDepthReport {
+    pub fn declared_bits(&self) -> u32 {
+        self.tracks
+            .iter()
+            .map(|track| track.bits_per_sample)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn used_bits(&self) -> Option<u32> {
+        self.tracks.iter().filter_map(|track| track.used_bits).max()
+    }
+
+    /// If the release claims to be hi-res, but no track uses more bits than a CD.
+    pub fn is_padded(&self) -> bool {
+        self.declared_bits() > CD_BITS_PER_SAMPLE
+            && self
+                .used_bits()
+                .is_some_and(|used_bits| used_bits <= CD_BITS_PER_SAMPLE)
+    }
+}
+
+impl fmt::Display for BitDepthReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.used_bits() {
+            Some(used_bits) => write!(
+                f,
+                "{}bit FLAC using at most {} bits in all {} track(s), the lowest {} bits are always zero",
+                self.declared_bits(),
+                used_bits,
+                self.tracks.len(),
+                self.declared_bits() - used_bits
+            ),
+            None => write!(f, "{}bit FLAC which is silent", self.declared_bits()),
+        }
+    }
+}
+
+/// Decodes every track and checks which low-order bits are never set, a 16bit recording padded
+/// to 24bit has its lowest 8 bits at zero in every sample.
+pub async fn analyze_bit_depth(
+    flac_dir: &PathBuf,
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<BitDepthReport> {
+    let paths = get_all_files_with_extension(flac_dir, ".flac").await?;
+
+    let results = join_all(paths.into_iter().map(|path| {
+        let semaphore = semaphore.clone();
+
+        async move {
+            let _permit = semaphore.acquire().await?;
+            tokio::task::spawn_blocking(move || track_bit_depth(path)).await?
+        }
+    }))
+    .await;
+
+    Ok(BitDepthReport {
+        tracks: results.into_iter().collect::<anyhow::Result<Vec<_>>>()?,
+    })
+}
+
+fn track_bit_depth(path: PathBuf) -> anyhow::Result<TrackBitDepth> {
+    let mut reader = FlacReader::open(&path)?;
+    let bits_per_sample = reader.streaminfo().bits_per_sample;
+
+    // Every bit set in any sample ends up in the mask, the trailing zeros of it are never used
+    let mut mask = 0i32;
+
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+
+    while let Some(block) = blocks.read_next_or_eof(buffer)? {
+        let samples = block.into_buffer();
+        mask = samples.iter().fold(mask, |mask, sample| mask | sample);
+
+        // The lowest bit is in use, the rest of the track can not change the result
+        if mask & 1 == 1 {
+            break;
+        }
+
+        buffer = samples;
+    }
+
+    Ok(TrackBitDepth {
+        bits_per_sample,
+        used_bits: used_bits(bits_per_sample, mask),
+    })
+}
+
+/// The bits above the lowest one set in any sample are in use, negative samples have all high
+/// bits set so only the trailing zeros count.
+fn used_bits(bits_per_sample: u32, mask: i32) -> Option<u32> {
+    match mask {
+        0 => None,
+        mask => Some(bits_per_sample.saturating_sub(mask.trailing_zeros())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_of(samples: &[i32]) -> i32 {
+        samples.iter().fold(0, |mask, sample| mask | sample)
+    }
+
+    #[test]
+    fn counts_bits_of_padded_samples() {
+        // 16bit samples shifted into 24bit, including negative ones
+        let samples = [1 << 8, -(1 << 8), -32768 << 8, 32767 << 8];
+
+        assert_eq!(used_bits(24, mask_of(&samples)), Some(16));
+    }
+
+    #[test]
+    fn negative_samples_alone_keep_their_trailing_zeros() {
+        assert_eq!(used_bits(24, mask_of(&[-(1 << 8)])), Some(16));
+        assert_eq!(used_bits(24, mask_of(&[-1])), Some(24));
+        assert_eq!(used_bits(24, mask_of(&[-(1 << 4), 3 << 4])), Some(20));
+    }
+
+    #[test]
+    fn silence_uses_no_bits() {
+        assert_eq!(used_bits(24, mask_of(&[0, 0, 0])), None);
+    }
+
+    #[test]
+    fn padded_only_if_no_track_uses_more_than_16_bits() {
+        let report = |used_bits: &[Option<u32>]| BitDepthReport {
+            tracks: used_bits
+                .iter()
+                .map(|used_bits| TrackBitDepth {
+                    bits_per_sample: 24,
+                    used_bits: *used_bits,
+                })
+                .collect(),
+        };
+
+        assert!(report(&[Some(16), Some(14), None]).is_padded());
+        assert!(!report(&[Some(16), Some(17)]).is_padded());
+        assert!(!report(&[None]).is_padded());
+    }
+}
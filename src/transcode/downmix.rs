@@ -0,0 +1,214 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+
+/// How much of an input channel goes to the left and the right output.
+type Weights = [f64; 2];
+
+const LEFT: Weights = [1.0, 0.0];
+const RIGHT: Weights = [0.0, 1.0];
+const CENTER: Weights = [FRAC_1_SQRT_2, FRAC_1_SQRT_2];
+const LFE: Weights = [0.0, 0.0];
+const LEFT_SURROUND: Weights = [FRAC_1_SQRT_2, 0.0];
+const RIGHT_SURROUND: Weights = [0.0, FRAC_1_SQRT_2];
+const BACK_CENTER: Weights = [0.5, 0.5];
+
+/// Mixes a multichannel source down to stereo with the ITU-R BS.775 coefficients, the center
+/// and surround channels are added at -3dB and the LFE is dropped. The coefficients are scaled
+/// down so that even full scale on every channel at once can not clip.
+#[derive(Debug, Clone)]
+pub struct Downmix {
+    channels: u32,
+    coefficients: Vec<Weights>,
+    gain: f64,
+}
+
+impl Downmix {
+    /// Returns `None` for channel counts without a standard layout.
+    pub fn new(channels: u32) -> Option<Self> {
+        let layout = channel_layout(channels)?;
+
+        let loudest_output = (0..2)
+            .map(|output| layout.iter().map(|weights| weights[output]).sum::<f64>())
+            .fold(0.0, f64::max);
+        let gain = 1.0 / loudest_output;
+
+        let coefficients = layout
+            .iter()
+            .map(|weights| [weights[0] * gain, weights[1] * gain])
+            .collect();
+
+        Some(Self {
+            channels,
+            coefficients,
+            gain,
+        })
+    }
+
+    pub fn apply(&self, input: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        let frames = input.first().map_or(0, |channel| channel.len());
+
+        (0..2)
+            .map(|output| {
+                (0..frames)
+                    .map(|frame| {
+                        input
+                            .iter()
+                            .zip(&self.coefficients)
+                            .map(|(channel, weights)| channel[frame] * weights[output])
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The arguments of the sox `remix` effect doing the same mix.
+    pub fn sox_remix_args(&self) -> Vec<String> {
+        let mut args = vec!["remix".to_string(), "-m".to_string()];
+
+        for output in 0..2 {
+            args.push(
+                self.weighted_inputs(output)
+                    .map(|(input, weight)| format!("{}v{:.6}", input + 1, weight))
+                    .collect::<Vec<String>>()
+                    .join(","),
+            );
+        }
+
+        args
+    }
+
+    /// The ffmpeg `pan` filter doing the same mix.
+    pub fn ffmpeg_pan_filter(&self) -> String {
+        let outputs = (0..2)
+            .map(|output| {
+                format!(
+                    "c{}={}",
+                    output,
+                    self.weighted_inputs(output)
+                        .map(|(input, weight)| format!("{:.6}*c{}", weight, input))
+                        .collect::<Vec<String>>()
+                        .join("+")
+                )
+            })
+            .collect::<Vec<String>>();
+
+        format!("pan=stereo|{}", outputs.join("|"))
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "ITU downmix {} to 2 channels with {:.1}dB clip protection",
+            self.channels,
+            20.0 * self.gain.log10()
+        )
+    }
+
+    fn weighted_inputs(&self, output: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        self.coefficients
+            .iter()
+            .enumerate()
+            .map(move |(input, weights)| (input, weights[output]))
+            .filter(|(_, weight)| *weight != 0.0)
+    }
+}
+
+/// The channel order FLAC defines for every channel count.
+fn channel_layout(channels: u32) -> Option<Vec<Weights>> {
+    let layout = match channels {
+        3 => vec![LEFT, RIGHT, CENTER],
+        4 => vec![LEFT, RIGHT, LEFT_SURROUND, RIGHT_SURROUND],
+        5 => vec![LEFT, RIGHT, CENTER, LEFT_SURROUND, RIGHT_SURROUND],
+        6 => vec![LEFT, RIGHT, CENTER, LFE, LEFT_SURROUND, RIGHT_SURROUND],
+        7 => vec![
+            LEFT,
+            RIGHT,
+            CENTER,
+            LFE,
+            BACK_CENTER,
+            LEFT_SURROUND,
+            RIGHT_SURROUND,
+        ],
+        8 => vec![
+            LEFT,
+            RIGHT,
+            CENTER,
+            LFE,
+            LEFT_SURROUND,
+            RIGHT_SURROUND,
+            LEFT_SURROUND,
+            RIGHT_SURROUND,
+        ],
+        _ => return None,
+    };
+
+    Some(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_channel_counts_without_layout() {
+        assert!(Downmix::new(1).is_none());
+        assert!(Downmix::new(2).is_none());
+        assert!(Downmix::new(9).is_none());
+    }
+
+    #[test]
+    fn scales_coefficients_against_clipping() {
+        for channels in 3..=8 {
+            let downmix = Downmix::new(channels).unwrap();
+
+            for output in 0..2 {
+                let sum: f64 = downmix.coefficients.iter().map(|w| w[output]).sum();
+                assert!((sum - 1.0).abs() < 1e-9, "{} channels: {}", channels, sum);
+            }
+        }
+    }
+
+    #[test]
+    fn mixes_five_one_with_itu_coefficients() {
+        let downmix = Downmix::new(6).unwrap();
+        let gain = 1.0 / (1.0 + 2.0 * FRAC_1_SQRT_2);
+
+        let input = vec![
+            vec![1.0],
+            vec![0.0],
+            vec![1.0],
+            vec![1.0],
+            vec![0.0],
+            vec![1.0],
+        ];
+        let output = downmix.apply(&input);
+
+        assert!((output[0][0] - gain * (1.0 + FRAC_1_SQRT_2)).abs() < 1e-9);
+        assert!((output[1][0] - gain * 2.0 * FRAC_1_SQRT_2).abs() < 1e-9);
+        assert!((downmix.gain - gain).abs() < 1e-9);
+    }
+
+    #[test]
+    fn builds_sox_remix_args() {
+        let downmix = Downmix::new(6).unwrap();
+
+        assert_eq!(
+            downmix.sox_remix_args(),
+            vec![
+                "remix",
+                "-m",
+                "1v0.414214,3v0.292893,5v0.292893",
+                "2v0.414214,3v0.292893,6v0.292893",
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_ffmpeg_pan_filter() {
+        let downmix = Downmix::new(3).unwrap();
+
+        assert_eq!(
+            downmix.ffmpeg_pan_filter(),
+            "pan=stereo|c0=0.585786*c0+0.414214*c2|c1=0.585786*c1+0.414214*c2"
+        );
+    }
+}
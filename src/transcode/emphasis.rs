@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::cue::models::CueSheet;
+use crate::cue::parser::read_cue_sheet;
+use crate::fs::util::{decode_text, get_all_files_with_extension};
+use crate::tags::util::EMPHASIS_TAG_KEYS;
+
+struct SourceTrack {
+    path: PathBuf,
+    number: Option<u32>,
+    emphasis_tag: Option<String>,
+}
+
+/// Finds the tracks of a release which were mastered with pre-emphasis, flagged by `FLAGS PRE`
+/// in a cue sheet, by a rip log or by a tag. Cue sheets and logs only apply to the FLACs in
+/// their own folder. Returns every flagged track with where the flag was found.
+pub async fn detect_pre_emphasis(flac_dir: &PathBuf) -> anyhow::Result<BTreeMap<PathBuf, String>> {
+    let mut tracks = vec![];
+
+    for path in get_all_files_with_extension(flac_dir, ".flac").await? {
+        let path_cloned = path.clone();
+        let tag = tokio::task::spawn_blocking(move || metaflac::Tag::read_from_path(path_cloned))
+            .await??;
+
+        let comment = |key: &str| {
+            tag.vorbis_comments().and_then(|comments| {
+                comments
+                    .comments
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                    .and_then(|(_, values)| values.first().cloned())
+            })
+        };
+
+        let number = comment("TRACKNUMBER").and_then(|value| parse_track_number(&value));
+        let emphasis_tag = EMPHASIS_TAG_KEYS
+            .iter()
+            .find(|key| comment(key).is_some_and(|value| is_truthy(&value)))
+            .map(|key| key.to_string());
+
+        tracks.push(SourceTrack {
+            path,
+            number,
+            emphasis_tag,
+        });
+    }
+
+    let mut emphasized = BTreeMap::new();
+
+    for track in &tracks {
+        if let Some(key) = &track.emphasis_tag {
+            emphasized.insert(track.path.clone(), format!("{} tag", key));
+        }
+    }
+
+    for cue_path in get_all_files_with_extension(flac_dir, ".cue").await? {
+        let sheet = read_cue_sheet(&cue_path).await?;
+        let source = format!("FLAGS PRE in \"{}\"", file_name(&cue_path));
+
+        for path in emphasized_tracks_of_cue(&sheet, &cue_path, &tracks) {
+            emphasized.entry(path).or_insert(source.clone());
+        }
+    }
+
+    for log_path in get_all_files_with_extension(flac_dir, ".log").await? {
+        let log = decode_text(&tokio::fs::read(&log_path).await?);
+        let source = format!("rip log \"{}\"", file_name(&log_path));
+
+        let flagged = emphasized_tracks_of_log(&log);
+
+        for track in tracks_in_folder_of(&log_path, &tracks) {
+            let is_flagged = match &flagged {
+                LogEmphasis::None => false,
+                LogEmphasis::Release => true,
+                LogEmphasis::Tracks(numbers) => {
+                    track.number.is_some_and(|number| numbers.contains(&number))
+                }
+            };
+
+            if is_flagged {
+                emphasized
+                    .entry(track.path.clone())
+                    .or_insert(source.clone());
+            }
+        }
+    }
+
+    Ok(emphasized)
+}
+
+/// A track of the cue sheet matches a FLAC with the same file name, cue sheets of images or
+/// ones pointing to the original WAVs are matched by track number instead.
+fn emphasized_tracks_of_cue(
+    sheet: &CueSheet,
+    cue_path: &Path,
+    tracks: &[SourceTrack],
+) -> Vec<PathBuf> {
+    let folder_tracks = tracks_in_folder_of(cue_path, tracks);
+    let mut emphasized = vec![];
+
+    for file in &sheet.files {
+        let file_stem = Path::new(&file.name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string());
+
+        let same_name = folder_tracks.iter().find(|track| {
+            track
+                .path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                == file_stem
+        });
+
+        for cue_track in file.tracks.iter().filter(|t| t.has_pre_emphasis()) {
+            match same_name {
+                Some(track) => emphasized.push(track.path.clone()),
+                None => emphasized.extend(
+                    folder_tracks
+                        .iter()
+                        .filter(|track| track.number == Some(cue_track.number))
+                        .map(|track| track.path.clone()),
+                ),
+            }
+        }
+    }
+
+    emphasized
+}
+
+enum LogEmphasis {
+    None,
+    Release,
+    Tracks(Vec<u32>),
+}
+
+/// EAC and XLD list pre-emphasis in the section of the affected track, a mention outside of any
+/// track section applies to the whole rip.
+fn emphasized_tracks_of_log(log: &str) -> LogEmphasis {
+    let mut current_track = None;
+    let mut tracks = vec![];
+
+    for line in log.lines() {
+        let trimmed = line.trim();
+
+        if let Some(rest) = trimmed.strip_prefix("Track") {
+            if let Some(number) = parse_track_number(rest.trim()) {
+                current_track = Some(number);
+                continue;
+            }
+        }
+
+        let lowercase = trimmed.to_lowercase();
+
+        if !lowercase.contains("pre-emphasis") && !lowercase.contains("preemphasis") {
+            continue;
+        }
+
+        // Lines like `Pre-emphasis : No` state the opposite
+        let value = lowercase.split_once(':').map(|(_, value)| value.trim());
+        if value.is_some_and(|value| !is_truthy(value)) {
+            continue;
+        }
+
+        match current_track {
+            Some(number) => tracks.push(number),
+            None => return LogEmphasis::Release,
+        }
+    }
+
+    if tracks.is_empty() {
+        LogEmphasis::None
+    } else {
+        LogEmphasis::Tracks(tracks)
+    }
+}
+
+fn tracks_in_folder_of<'a>(path: &Path, tracks: &'a [SourceTrack]) -> Vec<&'a SourceTrack> {
+    tracks
+        .iter()
+        .filter(|track| track.path.parent() == path.parent())
+        .collect()
+}
+
+/// Track numbers may be written as `1`, `01` or `1/12`.
+fn parse_track_number(value: &str) -> Option<u32> {
+    let digits = value
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>();
+
+    digits.parse().ok()
+}
+
+fn is_truthy(value: &str) -> bool {
+    !matches!(
+        value.trim().to_lowercase().as_str(),
+        "" | "0" | "no" | "false" | "off" | "none"
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
@@ -2,17 +2,51 @@ use std::path::PathBuf;
 
 use thiserror::Error;
 
+use crate::redacted::models::ReleaseType;
+
 #[derive(Error, Debug)]
 pub enum TranscodeError {
-    #[error("FLAC file \"{0}\" has a sample rate {1}, which is not 88.2 , 176.4 or 96kHz but needs resampling, this is unsupported")]
+    #[error("FLAC file \"{0}\" has a sample rate of {1}Hz, which is unsupported")]
     UnknownSampleRateError(PathBuf, u32),
 
     #[error("FLAC file \"{0}\" has more than 2 channels, unsupported")]
     TranscodeDownmixError(PathBuf),
 
+    #[error("FLAC file \"{0}\" has pre-emphasis, which the encoder profile can not undo")]
+    DeemphasisUnsupported(PathBuf),
+
+    #[error("FLAC file \"{0}\" is corrupt after {1} samples: {2}")]
+    CorruptSourceFrame(PathBuf, u64, String),
+
+    #[error("FLAC file \"{0}\" decodes to audio which does not match its MD5 signature")]
+    SourceMd5Mismatch(PathBuf),
+
     #[error("Output directory \"{0}\" already exists, aborting")]
     OutputDirectoryExist(PathBuf),
 
+    #[error("Partial output \"{0}\" of an earlier run already exists, aborting")]
+    PartialOutputExist(PathBuf),
+
     #[error("Some FLAC was incorrectly marked as 24bit.")]
     Invalid24BitFlac,
+
+    #[error("{step} step failed with exit code {}: `{command}`\n{stderr}", exit_code.map_or("<none>".to_string(), |c| c.to_string()))]
+    EncoderFailed {
+        step: String,
+        command: String,
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+
+    #[error("Output \"{0}\" decodes to {2} samples but {1} were expected")]
+    OutputLengthMismatch(PathBuf, u64, u64),
+
+    #[error("Transcoded track \"{0}\" failed verification: {1}")]
+    VerificationFailed(PathBuf, String),
+
+    #[error("LAME header of \"{0}\" does not match the expected encoder settings: {1}")]
+    LameHeaderMismatch(PathBuf, String),
+
+    #[error("{1} track(s) failed to transcode to {0}")]
+    TracksFailed(ReleaseType, usize),
 }
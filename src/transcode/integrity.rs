@@ -0,0 +1,156 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use futures::future::join_all;
+use tokio::sync::Semaphore;
+
+use crate::fs::util::get_all_files_with_extension;
+use crate::transcode::error::TranscodeError::{CorruptSourceFrame, SourceMd5Mismatch};
+
+/// Encoders which did not compute the MD5 signature leave it at zero.
+const MISSING_MD5: [u8; 16] = [0; 16];
+
+pub struct IntegrityReport {
+    pub checked_tracks: usize,
+    /// Tracks which decoded without errors, but have no MD5 signature to compare with
+    pub tracks_without_md5: Vec<PathBuf>,
+    pub failures: Vec<anyhow::Error>,
+}
+
+/// Does what `flac -t` does for every track of the release: decodes every frame, which checks
+/// the CRCs of the frames, and compares the decoded audio with the MD5 signature in streaminfo.
+pub async fn verify_source_integrity(
+    flac_dir: &PathBuf,
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<IntegrityReport> {
+    let paths = get_all_files_with_extension(flac_dir, ".flac").await?;
+
+    let results = join_all(paths.iter().map(|path| {
+        let semaphore = semaphore.clone();
+        let path = path.clone();
+
+        async move {
+            let _permit = semaphore.acquire().await?;
+            tokio::task::spawn_blocking(move || test_flac(&path)).await?
+        }
+    }))
+    .await;
+
+    let mut report = IntegrityReport {
+        checked_tracks: paths.len(),
+        tracks_without_md5: vec![],
+        failures: vec![],
+    };
+
+    for (path, result) in paths.into_iter().zip(results) {
+        match result {
+            Ok(true) => {}
+            Ok(false) => report.tracks_without_md5.push(path),
+            Err(e) => report.failures.push(e),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Returns if the MD5 signature could be compared, a mismatch or a corrupt frame is an error.
+fn test_flac(path: &PathBuf) -> anyhow::Result<bool> {
+    let mut reader = FlacReader::open(path)?;
+    let streaminfo = reader.streaminfo();
+
+    let mut context = md5::Context::new();
+    let mut bytes = Vec::new();
+    let mut decoded_samples = 0;
+
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+
+    loop {
+        let block = match blocks.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => break,
+            // claxon checks the CRC of every frame header and frame while decoding
+            Err(e) => {
+                return Err(CorruptSourceFrame(path.clone(), decoded_samples, e.to_string()).into())
+            }
+        };
+
+        bytes.clear();
+
+        for i in 0..block.duration() {
+            for channel in 0..block.channels() {
+                push_md5_sample(
+                    &mut bytes,
+                    block.sample(channel, i),
+                    streaminfo.bits_per_sample,
+                );
+            }
+        }
+
+        context.consume(&bytes);
+        decoded_samples += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+
+    if streaminfo.md5sum == MISSING_MD5 {
+        return Ok(false);
+    }
+
+    if context.compute().0 != streaminfo.md5sum {
+        return Err(SourceMd5Mismatch(path.clone()).into());
+    }
+
+    Ok(true)
+}
+
+/// The MD5 signature covers the samples in little endian, sign-extended to whole bytes.
+fn push_md5_sample(bytes: &mut Vec<u8>, sample: i32, bits_per_sample: u32) {
+    let bytes_per_sample = bits_per_sample.div_ceil(8) as usize;
+
+    bytes.extend_from_slice(&sample.to_le_bytes()[..bytes_per_sample]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed(samples: &[i32], bits_per_sample: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        for sample in samples {
+            push_md5_sample(&mut bytes, *sample, bits_per_sample);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn packs_12_bit_into_two_bytes() {
+        assert_eq!(
+            packed(&[0x7ff, -1, -0x800], 12),
+            vec![0xff, 0x07, 0xff, 0xff, 0x00, 0xf8]
+        );
+    }
+
+    #[test]
+    fn packs_20_bit_into_three_bytes() {
+        assert_eq!(
+            packed(&[0x7ffff, -2], 20),
+            vec![0xff, 0xff, 0x07, 0xfe, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn packs_24_bit_into_three_bytes() {
+        assert_eq!(
+            packed(&[0x123456, -0x800000], 24),
+            vec![0x56, 0x34, 0x12, 0x00, 0x00, 0x80]
+        );
+    }
+
+    #[test]
+    fn packs_16_bit_like_pcm() {
+        assert_eq!(packed(&[1, -32768], 16), vec![0x01, 0x00, 0x00, 0x80]);
+    }
+}
@@ -0,0 +1,451 @@
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use claxon::FlacReader;
+
+use crate::redacted::models::ReleaseType;
+use crate::redacted::models::ReleaseType::{Mp3320, Mp3V0, Mp3V2};
+use crate::transcode::error::TranscodeError::LameHeaderMismatch;
+use crate::transcode::sample_rate::SampleRatePlan;
+
+/// How many bytes after the ID3v2 tag are searched for the first MPEG frame.
+const FRAME_SEARCH_SIZE: usize = 8 * 1024;
+
+const MPEG1_LAYER3_BITRATES: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MPEG2_LAYER3_BITRATES: [u32; 15] =
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const MPEG1_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelMode {
+    Stereo,
+    JointStereo,
+    DualChannel,
+    Mono,
+}
+
+impl fmt::Display for ChannelMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelMode::Stereo => write!(f, "stereo"),
+            ChannelMode::JointStereo => write!(f, "joint stereo"),
+            ChannelMode::DualChannel => write!(f, "dual channel"),
+            ChannelMode::Mono => write!(f, "mono"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VbrMethod {
+    Unknown,
+    Cbr,
+    Abr,
+    VbrRh,
+    VbrMtrh,
+    VbrMt,
+    Cbr2Pass,
+    Abr2Pass,
+}
+
+impl From<u8> for VbrMethod {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => VbrMethod::Cbr,
+            2 => VbrMethod::Abr,
+            3 => VbrMethod::VbrRh,
+            4 => VbrMethod::VbrMtrh,
+            5 => VbrMethod::VbrMt,
+            8 => VbrMethod::Cbr2Pass,
+            9 => VbrMethod::Abr2Pass,
+            _ => VbrMethod::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for VbrMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VbrMethod::Unknown => write!(f, "unknown"),
+            VbrMethod::Cbr => write!(f, "CBR"),
+            VbrMethod::Abr => write!(f, "ABR"),
+            VbrMethod::VbrRh => write!(f, "VBR (rh)"),
+            VbrMethod::VbrMtrh => write!(f, "VBR (mtrh)"),
+            VbrMethod::VbrMt => write!(f, "VBR (mt)"),
+            VbrMethod::Cbr2Pass => write!(f, "CBR (2-pass)"),
+            VbrMethod::Abr2Pass => write!(f, "ABR (2-pass)"),
+        }
+    }
+}
+
+/// The encoder settings LAME recorded in the Xing/Info and LAME tags of the first frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LameHeader {
+    pub encoder: String,
+    pub vbr_method: VbrMethod,
+    pub vbr_quality: Option<u32>,
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub channel_mode: ChannelMode,
+}
+
+impl fmt::Display for LameHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.vbr_method {
+            VbrMethod::VbrRh | VbrMethod::VbrMtrh | VbrMethod::VbrMt => write!(
+                f,
+                "{}, {} V{}",
+                self.encoder,
+                self.vbr_method,
+                self.vbr_quality
+                    .map_or("?".to_string(), |quality| quality.to_string())
+            )?,
+            _ => write!(
+                f,
+                "{}, {} {}kbps",
+                self.encoder, self.vbr_method, self.bitrate
+            )?,
+        }
+
+        write!(
+            f,
+            ", {:.1}kHz {}",
+            self.sample_rate as f64 / 1000.0,
+            self.channel_mode
+        )
+    }
+}
+
+/// Reads the Xing/Info and LAME tags of the given MP3 file.
+pub async fn read_lame_header(path: &Path) -> anyhow::Result<LameHeader> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+
+        let mut id3_header = [0u8; 10];
+        file.read_exact(&mut id3_header)?;
+
+        let mut frame_search_start = 0;
+
+        if &id3_header[0..3] == b"ID3" {
+            let size = id3_header[6..10]
+                .iter()
+                .fold(0u64, |size, byte| (size << 7) | (*byte & 0x7f) as u64);
+            let footer = if id3_header[5] & 0x10 != 0 { 10 } else { 0 };
+
+            frame_search_start = 10 + size + footer;
+        }
+
+        file.seek(SeekFrom::Start(frame_search_start))?;
+
+        let mut buffer = Vec::with_capacity(FRAME_SEARCH_SIZE);
+        file.take(FRAME_SEARCH_SIZE as u64)
+            .read_to_end(&mut buffer)?;
+
+        parse_lame_header(&buffer).ok_or_else(|| {
+            anyhow::anyhow!("No Xing/Info and LAME tag found in \"{}\"", path.display())
+        })
+    })
+    .await?
+}
+
+/// Checks that the LAME header of a transcoded track matches the settings the given format is
+/// supposed to be encoded with and the sample rate planned for the release.
+pub async fn verify_lame_header(
+    source: &Path,
+    output: &Path,
+    format: ReleaseType,
+    sample_rate_plan: &SampleRatePlan,
+) -> anyhow::Result<LameHeader> {
+    let header = read_lame_header(output).await?;
+
+    let source_cloned = source.to_path_buf();
+    let streaminfo = tokio::task::spawn_blocking(move || FlacReader::open(source_cloned))
+        .await??
+        .streaminfo();
+
+    let mismatch = |reason: String| LameHeaderMismatch(output.to_path_buf(), reason);
+    let expected_sample_rate = sample_rate_plan.mp3_target_sample_rate();
+
+    if !header.encoder.starts_with("LAME") {
+        return Err(mismatch(format!("encoded by {} instead of LAME", header.encoder)).into());
+    }
+
+    match format {
+        Mp3V0 | Mp3V2 => {
+            if !matches!(
+                header.vbr_method,
+                VbrMethod::VbrRh | VbrMethod::VbrMtrh | VbrMethod::VbrMt
+            ) {
+                return Err(mismatch(format!("{} instead of VBR", header.vbr_method)).into());
+            }
+
+            let expected_quality = if format == Mp3V0 { 0 } else { 2 };
+
+            if header.vbr_quality != Some(expected_quality) {
+                return Err(mismatch(format!(
+                    "VBR quality V{} instead of V{}",
+                    header
+                        .vbr_quality
+                        .map_or("?".to_string(), |quality| quality.to_string()),
+                    expected_quality
+                ))
+                .into());
+            }
+        }
+        Mp3320 => {
+            if !matches!(header.vbr_method, VbrMethod::Cbr | VbrMethod::Cbr2Pass) {
+                return Err(mismatch(format!("{} instead of CBR", header.vbr_method)).into());
+            }
+
+            // MPEG-2 and MPEG-2.5 (below 32kHz) top out at 160kbps
+            let expected_bitrate = if expected_sample_rate < 32000 {
+                160
+            } else {
+                320
+            };
+
+            if header.bitrate != expected_bitrate {
+                return Err(mismatch(format!(
+                    "{}kbps instead of {}kbps",
+                    header.bitrate, expected_bitrate
+                ))
+                .into());
+            }
+        }
+        _ => return Err(mismatch(format!("{} is not an MP3 format", format)).into()),
+    }
+
+    if header.sample_rate != expected_sample_rate {
+        return Err(mismatch(format!(
+            "sample rate {}Hz instead of {}Hz",
+            header.sample_rate, expected_sample_rate
+        ))
+        .into());
+    }
+
+    let channel_mode_matches = match streaminfo.channels {
+        1 => header.channel_mode == ChannelMode::Mono,
+        _ => matches!(
+            header.channel_mode,
+            ChannelMode::Stereo | ChannelMode::JointStereo
+        ),
+    };
+
+    if !channel_mode_matches {
+        return Err(mismatch(format!(
+            "channel mode {} for a source with {} channel(s)",
+            header.channel_mode, streaminfo.channels
+        ))
+        .into());
+    }
+
+    Ok(header)
+}
+
+fn parse_lame_header(buffer: &[u8]) -> Option<LameHeader> {
+    let frame_start = (0..buffer.len().saturating_sub(4))
+        .find(|&i| buffer[i] == 0xff && buffer[i + 1] & 0xe0 == 0xe0)?;
+    let frame = &buffer[frame_start..];
+
+    let version = (frame[1] >> 3) & 0x03;
+    let layer = (frame[1] >> 1) & 0x03;
+
+    // Only MPEG Layer III carries a Xing/Info tag, version 1 is reserved
+    if layer != 1 || version == 1 {
+        return None;
+    }
+
+    let is_mpeg1 = version == 3;
+    let bitrate_index = (frame[2] >> 4) as usize;
+    let sample_rate_index = ((frame[2] >> 2) & 0x03) as usize;
+
+    if bitrate_index == 0x0f || sample_rate_index == 3 {
+        return None;
+    }
+
+    let bitrate = if is_mpeg1 {
+        MPEG1_LAYER3_BITRATES[bitrate_index]
+    } else {
+        MPEG2_LAYER3_BITRATES[bitrate_index]
+    };
+
+    let sample_rate = match version {
+        3 => MPEG1_SAMPLE_RATES[sample_rate_index],
+        2 => MPEG1_SAMPLE_RATES[sample_rate_index] / 2,
+        _ => MPEG1_SAMPLE_RATES[sample_rate_index] / 4,
+    };
+
+    let channel_mode = match frame[3] >> 6 {
+        0 => ChannelMode::Stereo,
+        1 => ChannelMode::JointStereo,
+        2 => ChannelMode::DualChannel,
+        _ => ChannelMode::Mono,
+    };
+
+    let side_info_size = match (is_mpeg1, channel_mode) {
+        (true, ChannelMode::Mono) => 17,
+        (true, _) => 32,
+        (false, ChannelMode::Mono) => 9,
+        (false, _) => 17,
+    };
+
+    // A cleared protection bit means a 16 bit CRC follows the header (e.g. `lame -p`)
+    let crc_size = if frame[1] & 0x01 == 0 { 2 } else { 0 };
+
+    let mut offset = 4 + crc_size + side_info_size;
+
+    let tag_id = frame.get(offset..offset + 4)?;
+    if tag_id != b"Xing" && tag_id != b"Info" {
+        return None;
+    }
+
+    let flags = u32::from_be_bytes(frame.get(offset + 4..offset + 8)?.try_into().ok()?);
+    offset += 8;
+
+    // Frame count, byte count and TOC are skipped, only the quality is of interest
+    if flags & 0x01 != 0 {
+        offset += 4;
+    }
+    if flags & 0x02 != 0 {
+        offset += 4;
+    }
+    if flags & 0x04 != 0 {
+        offset += 100;
+    }
+
+    let mut vbr_quality = None;
+
+    if flags & 0x08 != 0 {
+        let quality = u32::from_be_bytes(frame.get(offset..offset + 4)?.try_into().ok()?);
+
+        // LAME stores 100 - 10 * VBR quality - algorithm quality
+        if quality <= 100 {
+            vbr_quality = Some((100 - quality) / 10);
+        }
+
+        offset += 4;
+    }
+
+    let encoder = frame.get(offset..offset + 9)?;
+    let encoder = String::from_utf8_lossy(encoder)
+        .trim_end_matches(|c: char| !c.is_ascii_alphanumeric())
+        .to_string();
+
+    let vbr_method = VbrMethod::from(frame.get(offset + 9)? & 0x0f);
+
+    Some(LameHeader {
+        encoder,
+        vbr_method,
+        vbr_quality,
+        bitrate,
+        sample_rate,
+        channel_mode,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the first frame of a LAME encode: the frame header, empty side info, the Xing/Info
+    /// tag with frame count, byte count and quality and the start of the LAME tag.
+    fn lame_frame(header: [u8; 4], tag_id: &[u8; 4], quality: u32, vbr_method: u8) -> Vec<u8> {
+        let mpeg1 = (header[1] >> 3) & 0x03 == 3;
+        let mono = header[3] >> 6 == 3;
+        let crc = header[1] & 0x01 == 0;
+
+        let side_info_size = match (mpeg1, mono) {
+            (true, true) => 17,
+            (true, false) => 32,
+            (false, true) => 9,
+            (false, false) => 17,
+        };
+
+        let mut frame = header.to_vec();
+
+        if crc {
+            frame.extend_from_slice(&[0xab, 0xcd]);
+        }
+
+        frame.resize(frame.len() + side_info_size, 0);
+        frame.extend_from_slice(tag_id);
+        frame.extend_from_slice(&(0x01u32 | 0x02 | 0x08).to_be_bytes());
+        frame.extend_from_slice(&1000u32.to_be_bytes());
+        frame.extend_from_slice(&500_000u32.to_be_bytes());
+        frame.extend_from_slice(&quality.to_be_bytes());
+        frame.extend_from_slice(b"LAME3.100");
+        frame.push(vbr_method);
+        frame.resize(frame.len() + 400, 0);
+
+        frame
+    }
+
+    #[test]
+    fn parses_vbr_header() {
+        // MPEG-1 Layer III, no CRC, 128kbps slot, 44.1kHz, joint stereo
+        let frame = lame_frame([0xff, 0xfb, 0x90, 0x64], b"Xing", 100, 0x04);
+
+        let header = parse_lame_header(&frame).unwrap();
+
+        assert_eq!(header.encoder, "LAME3.100");
+        assert_eq!(header.vbr_method, VbrMethod::VbrMtrh);
+        assert_eq!(header.vbr_quality, Some(0));
+        assert_eq!(header.sample_rate, 44100);
+        assert_eq!(header.channel_mode, ChannelMode::JointStereo);
+    }
+
+    #[test]
+    fn parses_cbr_header() {
+        // MPEG-1 Layer III, no CRC, 320kbps, 48kHz, stereo
+        let frame = lame_frame([0xff, 0xfb, 0xe4, 0x04], b"Info", 58, 0x01);
+
+        let header = parse_lame_header(&frame).unwrap();
+
+        assert_eq!(header.vbr_method, VbrMethod::Cbr);
+        assert_eq!(header.bitrate, 320);
+        assert_eq!(header.sample_rate, 48000);
+        assert_eq!(header.channel_mode, ChannelMode::Stereo);
+    }
+
+    #[test]
+    fn parses_crc_protected_frames() {
+        // Same as above but with the protection bit cleared, so a CRC sits after the header
+        let frame = lame_frame([0xff, 0xfa, 0x90, 0x64], b"Xing", 80, 0x04);
+
+        let header = parse_lame_header(&frame).unwrap();
+
+        assert_eq!(header.encoder, "LAME3.100");
+        assert_eq!(header.vbr_quality, Some(2));
+    }
+
+    #[test]
+    fn parses_mpeg2_mono_header() {
+        // MPEG-2 Layer III, CRC, 22.05kHz, mono
+        let frame = lame_frame([0xff, 0xf2, 0x80, 0xc0], b"Xing", 100, 0x04);
+
+        let header = parse_lame_header(&frame).unwrap();
+
+        assert_eq!(header.sample_rate, 22050);
+        assert_eq!(header.bitrate, 64);
+        assert_eq!(header.channel_mode, ChannelMode::Mono);
+    }
+
+    #[test]
+    fn finds_frame_after_leading_garbage() {
+        let mut buffer = vec![0x00, 0x12, 0x34];
+        buffer.extend(lame_frame([0xff, 0xfb, 0x90, 0x64], b"Xing", 100, 0x04));
+
+        assert!(parse_lame_header(&buffer).is_some());
+    }
+
+    #[test]
+    fn rejects_frames_without_tag() {
+        let frame = lame_frame([0xff, 0xfb, 0x90, 0x64], b"Nope", 100, 0x04);
+
+        assert_eq!(parse_lame_header(&frame), None);
+    }
+}
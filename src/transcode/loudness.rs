@@ -0,0 +1,532 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use futures::future::join_all;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::sync::Semaphore;
+
+use crate::fs::util::get_all_files_with_extension;
+use crate::redacted::models::ReleaseType;
+use crate::redacted::models::ReleaseType::{Flac, Flac24, Mp3320, Mp3V0, Mp3V2};
+use crate::tags::replaygain::{write_replaygain_tags, ReplayGainValues};
+
+/// ReplayGain 2.0 aims every track and album at -18 LUFS.
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Gating of EBU R128 / ITU-R BS.1770, blocks quieter than the absolute gate are silence and
+/// blocks more than the relative gate below the loudness of the rest are ignored.
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Loudness is measured over blocks of 400ms, overlapping by 75%.
+const SUB_BLOCKS_PER_SECOND: u32 = 10;
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+
+/// The largest positive value of a 16bit sample, anything at or above it is full scale.
+const CLIP_THRESHOLD: f64 = 32767.0 / 32768.0;
+
+/// A single full scale sample can be a legitimate peak, clipping shows up as a run of them.
+const CLIP_RUN_LENGTH: usize = 3;
+
+/// Only formats with a place for ReplayGain tags are measured.
+pub fn supports_replaygain(format: &ReleaseType) -> bool {
+    matches!(format, Flac24 | Flac | Mp3320 | Mp3V0 | Mp3V2)
+}
+
+/// The loudness of one transcoded release, as shown in the release summary.
+#[derive(Debug, Clone)]
+pub struct LoudnessReport {
+    pub album_gain: f64,
+    pub album_peak: f64,
+    pub clipping_tracks: usize,
+}
+
+impl fmt::Display for LoudnessReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "album gain {:+.2} dB", self.album_gain)?;
+
+        // Silence has no level on the dB scale
+        if self.album_peak > 0.0 {
+            write!(f, ", peak {:.2} dBFS", 20.0 * self.album_peak.log10())?;
+        } else {
+            write!(f, ", silent")?;
+        }
+
+        if self.clipping_tracks > 0 {
+            write!(f, ", {} track(s) clipping", self.clipping_tracks)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Measures the loudness of every FLAC and MP3 of a transcoded release from its decoded audio
+/// and writes track and album ReplayGain tags into them.
+pub async fn apply_replaygain(
+    release_dir: &PathBuf,
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<LoudnessReport> {
+    let mut paths = get_all_files_with_extension(release_dir, ".flac").await?;
+    paths.extend(get_all_files_with_extension(release_dir, ".mp3").await?);
+
+    let results = join_all(paths.iter().map(|path| {
+        let semaphore = semaphore.clone();
+        let path = path.clone();
+
+        async move {
+            let _permit = semaphore.acquire().await?;
+            tokio::task::spawn_blocking(move || measure_track(&path)).await?
+        }
+    }))
+    .await;
+
+    let tracks = results.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+
+    let album_blocks = tracks
+        .iter()
+        .flat_map(|track| track.blocks.iter().copied())
+        .collect::<Vec<f64>>();
+    let album_gain = gain_of(gated_loudness(&album_blocks)).unwrap_or(0.0);
+    let album_peak = tracks.iter().map(|track| track.peak).fold(0.0, f64::max);
+
+    for (path, track) in paths.iter().zip(&tracks) {
+        let values = ReplayGainValues {
+            track_gain: gain_of(gated_loudness(&track.blocks)).unwrap_or(album_gain),
+            track_peak: track.peak,
+            album_gain,
+            album_peak,
+        };
+
+        write_replaygain_tags(path, values).await?;
+    }
+
+    Ok(LoudnessReport {
+        album_gain,
+        album_peak,
+        clipping_tracks: tracks.iter().filter(|track| track.clipped_runs > 0).count(),
+    })
+}
+
+struct TrackLoudness {
+    /// The mean square of every 400ms block after K-weighting
+    blocks: Vec<f64>,
+    peak: f64,
+    /// How many times a channel stayed at full scale for at least `CLIP_RUN_LENGTH` samples
+    clipped_runs: u64,
+}
+
+fn gain_of(loudness: Option<f64>) -> Option<f64> {
+    loudness.map(|loudness| REFERENCE_LOUDNESS - loudness)
+}
+
+fn loudness_of(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// The gated loudness in LUFS, `None` if every block is below the absolute gate.
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let above_absolute = blocks
+        .iter()
+        .copied()
+        .filter(|power| loudness_of(*power) > ABSOLUTE_GATE)
+        .collect::<Vec<f64>>();
+
+    if above_absolute.is_empty() {
+        return None;
+    }
+
+    let relative_gate = loudness_of(mean(&above_absolute)) + RELATIVE_GATE;
+
+    let above_relative = above_absolute
+        .into_iter()
+        .filter(|power| loudness_of(*power) > relative_gate)
+        .collect::<Vec<f64>>();
+
+    Some(loudness_of(mean(&above_relative)))
+}
+
+fn measure_track(path: &PathBuf) -> anyhow::Result<TrackLoudness> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_str().unwrap().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "flac" => measure_flac(path),
+        "mp3" => measure_symphonia(path, &extension),
+        _ => Err(anyhow::anyhow!(
+            "Can not measure the loudness of \"{}\", unsupported file type",
+            path.display()
+        )),
+    }
+}
+
+fn measure_flac(path: &PathBuf) -> anyhow::Result<TrackLoudness> {
+    let mut reader = FlacReader::open(path)?;
+    let streaminfo = reader.streaminfo();
+    let scale = 1.0 / (1i64 << (streaminfo.bits_per_sample - 1)) as f64;
+
+    let mut meter = LoudnessMeter::new(streaminfo.sample_rate, streaminfo.channels as usize);
+    let mut frame = vec![0.0; streaminfo.channels as usize];
+
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+
+    while let Some(block) = blocks.read_next_or_eof(buffer)? {
+        for i in 0..block.duration() {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = block.sample(channel as u32, i) as f64 * scale;
+            }
+
+            meter.push_frame(&frame);
+        }
+
+        buffer = block.into_buffer();
+    }
+
+    Ok(meter.finish())
+}
+
+fn measure_symphonia(path: &PathBuf, extension: &str) -> anyhow::Result<TrackLoudness> {
+    let file = std::fs::File::open(path)?;
+    let media_source = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        media_source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let mut format = probed.format;
+
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow::anyhow!("No audio track found in \"{}\"", path.display()))?;
+    let track_id = track.id;
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut meter: Option<LoudnessMeter> = None;
+    let mut sample_buffer: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+
+        let buffer =
+            sample_buffer.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        buffer.copy_interleaved_ref(decoded);
+
+        let meter = meter.get_or_insert_with(|| LoudnessMeter::new(spec.rate, channels));
+
+        for frame in buffer.samples().chunks_exact(channels) {
+            let frame = frame.iter().map(|s| *s as f64).collect::<Vec<f64>>();
+            meter.push_frame(&frame);
+        }
+    }
+
+    meter
+        .map(LoudnessMeter::finish)
+        .ok_or_else(|| anyhow::anyhow!("No audio decoded from \"{}\"", path.display()))
+}
+
+/// A second order IIR filter in direct form I.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+/// The high shelf of the K-weighting modelling the head, designed for the given sample rate so
+/// it matches the coefficients BS.1770 lists for 48kHz.
+fn head_shelf(sample_rate: u32) -> Biquad {
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+
+    let k = (PI * f0 / sample_rate as f64).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+
+    Biquad::new(
+        [
+            vh + vb * k / q + k * k,
+            2.0 * (k * k - vh),
+            vh - vb * k / q + k * k,
+        ],
+        [
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ],
+    )
+}
+
+/// The high pass of the K-weighting (RLB weighting), designed for the given sample rate.
+fn low_cut(sample_rate: u32) -> Biquad {
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+
+    let k = (PI * f0 / sample_rate as f64).tan();
+    let a0 = 1.0 + k / q + k * k;
+
+    // BS.1770 does not normalize the numerator of the high pass
+    Biquad::new(
+        [a0, -2.0 * a0, a0],
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    )
+}
+
+/// Collects the K-weighted power of a track in 100ms sub-blocks and combines every four of them
+/// into a 400ms block. Transcodes are mono or stereo, so every channel is weighted equally.
+struct LoudnessMeter {
+    filters: Vec<(Biquad, Biquad)>,
+    sub_block_length: usize,
+    sub_block_frames: usize,
+    sub_block_power: f64,
+    sub_blocks: Vec<f64>,
+    peak: f64,
+    /// The number of consecutive full scale samples of every channel up to now
+    full_scale_runs: Vec<usize>,
+    clipped_runs: u64,
+}
+
+impl LoudnessMeter {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        Self {
+            filters: vec![(head_shelf(sample_rate), low_cut(sample_rate)); channels],
+            sub_block_length: (sample_rate / SUB_BLOCKS_PER_SECOND).max(1) as usize,
+            sub_block_frames: 0,
+            sub_block_power: 0.0,
+            sub_blocks: vec![],
+            peak: 0.0,
+            full_scale_runs: vec![0; channels],
+            clipped_runs: 0,
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[f64]) {
+        for ((sample, (shelf, low_cut)), run) in frame
+            .iter()
+            .zip(self.filters.iter_mut())
+            .zip(self.full_scale_runs.iter_mut())
+        {
+            let magnitude = sample.abs();
+
+            self.peak = self.peak.max(magnitude);
+
+            if magnitude >= CLIP_THRESHOLD {
+                *run += 1;
+
+                // Counted once when the run gets long enough, not for every sample after
+                if *run == CLIP_RUN_LENGTH {
+                    self.clipped_runs += 1;
+                }
+            } else {
+                *run = 0;
+            }
+
+            let weighted = low_cut.process(shelf.process(*sample));
+            self.sub_block_power += weighted * weighted;
+        }
+
+        self.sub_block_frames += 1;
+
+        if self.sub_block_frames == self.sub_block_length {
+            self.sub_blocks
+                .push(self.sub_block_power / self.sub_block_length as f64);
+            self.sub_block_frames = 0;
+            self.sub_block_power = 0.0;
+        }
+    }
+
+    /// A trailing partial sub-block is dropped, like BS.1770 drops incomplete blocks.
+    fn finish(self) -> TrackLoudness {
+        let blocks = self
+            .sub_blocks
+            .windows(SUB_BLOCKS_PER_BLOCK)
+            .map(|window| window.iter().sum::<f64>() / SUB_BLOCKS_PER_BLOCK as f64)
+            .collect();
+
+        TrackLoudness {
+            blocks,
+            peak: self.peak,
+            clipped_runs: self.clipped_runs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(sample_rate: u32, frames: &[Vec<f64>]) -> TrackLoudness {
+        let mut meter = LoudnessMeter::new(sample_rate, frames[0].len());
+
+        for frame in frames {
+            meter.push_frame(frame);
+        }
+
+        meter.finish()
+    }
+
+    fn sine(sample_rate: u32, frequency: f64, amplitude: f64, seconds: u32) -> Vec<Vec<f64>> {
+        (0..sample_rate * seconds)
+            .map(|i| {
+                let sample =
+                    amplitude * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin();
+                vec![sample, sample]
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-8,
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn matches_bs1770_coefficients_at_48khz() {
+        let shelf = head_shelf(48000);
+        assert_close(
+            &shelf.b,
+            &[1.53512485958697, -2.69169618940638, 1.19839281085285],
+        );
+        assert_close(&shelf.a, &[-1.69065929318241, 0.73248077421585]);
+
+        let low_cut = low_cut(48000);
+        assert_close(&low_cut.b, &[1.0, -2.0, 1.0]);
+        assert_close(&low_cut.a, &[-1.99004745483398, 0.99007225036621]);
+    }
+
+    #[test]
+    fn measures_full_scale_sine() {
+        // BS.1770 defines a 997Hz sine at full scale on one channel as -3.01 LKFS, on both
+        // channels it is twice the power
+        let track = measure(48000, &sine(48000, 997.0, 1.0, 3));
+
+        let loudness = gated_loudness(&track.blocks).unwrap();
+
+        assert!(loudness.abs() < 0.05, "{}", loudness);
+        assert!((gain_of(Some(loudness)).unwrap() + 18.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn gates_silence() {
+        let track = measure(44100, &vec![vec![0.0, 0.0]; 44100]);
+
+        assert_eq!(gated_loudness(&track.blocks), None);
+        assert_eq!(track.peak, 0.0);
+    }
+
+    #[test]
+    fn counts_runs_of_full_scale_samples_as_clipping() {
+        let mut frames = vec![vec![0.0, 0.0]; 100];
+
+        // A lone full scale peak on the left and a run of them on the right
+        frames[10][0] = 1.0;
+        for frame in &mut frames[20..30] {
+            frame[1] = -1.0;
+        }
+
+        let track = measure(44100, &frames);
+
+        assert_eq!(track.clipped_runs, 1);
+        assert_eq!(track.peak, 1.0);
+    }
+
+    #[test]
+    fn ignores_short_full_scale_runs() {
+        let mut frames = vec![vec![0.0]; 100];
+
+        frames[10][0] = 1.0;
+        frames[11][0] = 1.0;
+        frames[50][0] = 1.0;
+
+        assert_eq!(measure(44100, &frames).clipped_runs, 0);
+    }
+
+    #[test]
+    fn reports_silent_release_without_peak() {
+        let report = LoudnessReport {
+            album_gain: 0.0,
+            album_peak: 0.0,
+            clipping_tracks: 0,
+        };
+
+        assert_eq!(report.to_string(), "album gain +0.00 dB, silent");
+    }
+
+    #[test]
+    fn reports_peak_in_dbfs() {
+        let report = LoudnessReport {
+            album_gain: -3.5,
+            album_peak: 0.5,
+            clipping_tracks: 2,
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "album gain -3.50 dB, peak -6.02 dBFS, 2 track(s) clipping"
+        );
+    }
+}
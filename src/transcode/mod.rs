@@ -1,3 +1,13 @@
+pub mod backend;
+pub mod bit_depth;
+pub mod downmix;
+pub mod emphasis;
 pub mod error;
+pub mod integrity;
+pub mod lame;
+pub mod loudness;
+pub mod native;
+pub mod sample_rate;
 pub mod transcode;
 pub mod util;
+pub mod verify;
@@ -0,0 +1,670 @@
+use std::path::PathBuf;
+
+use claxon::{Block, FlacReader};
+use rubato::{FftFixedIn, Resampler};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::transcode::downmix::Downmix;
+
+/// How many frames the resampler processes at once.
+const RESAMPLER_CHUNK_SIZE: usize = 8192;
+
+/// How many decoded chunks may be queued for the encoders before decoding pauses.
+const PCM_CHANNEL_CAPACITY: usize = 4;
+
+/// Fixed seed so the dither noise, and with it the transcode, is reproducible.
+const DITHER_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Time constants of the 50/15µs CD pre-emphasis, the pole and the zero of the de-emphasis.
+const EMPHASIS_POLE_TIME_CONSTANT: f64 = 50e-6;
+const EMPHASIS_ZERO_TIME_CONSTANT: f64 = 15e-6;
+
+/// The audio the native decoder produces, always interleaved signed little endian PCM.
+#[derive(Debug, Clone, Copy)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_sample: u32,
+}
+
+/// What happens to the audio of a source between decoding and the encoders, the same for the
+/// native decoder and the external decoders of the backends.
+#[derive(Debug, Clone)]
+pub struct DecodeSettings {
+    /// The rate to resample to, `None` keeps the rate of the source
+    pub target_sample_rate: Option<u32>,
+    pub target_bits_per_sample: u32,
+    /// Mixes a multichannel source down to stereo
+    pub downmix: Option<Downmix>,
+    /// Undoes the pre-emphasis of the source
+    pub deemphasis: bool,
+    /// The source uses no more bits than the target (e.g. 16bit audio padded to 24bit), so
+    /// reducing its bit depth only drops zeros and needs no dither
+    pub padded_source: bool,
+}
+
+pub struct NativeDecoder {
+    pub handle: JoinHandle<anyhow::Result<()>>,
+    pub receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+/// Decodes an image once for all of its tracks, every track has its own receiver.
+pub struct RangeDecoder {
+    pub handle: JoinHandle<anyhow::Result<()>>,
+    pub receivers: Vec<mpsc::Receiver<Vec<u8>>>,
+}
+
+/// Describes what the native decoder does with a source, used for the reproducible command
+/// string in the description.
+pub fn describe_native_pipeline(
+    source_sample_rate: u32,
+    source_bits_per_sample: u32,
+    settings: &DecodeSettings,
+) -> String {
+    let mut steps = vec!["claxon decode".to_string()];
+
+    if settings.deemphasis {
+        steps.push("50/15µs de-emphasis".to_string());
+    }
+
+    if let Some(downmix) = &settings.downmix {
+        steps.push(downmix.describe());
+    }
+
+    let resample = settings
+        .target_sample_rate
+        .filter(|rate| *rate != source_sample_rate);
+
+    if let Some(rate) = resample {
+        steps.push(format!(
+            "rubato FFT resample {}Hz to {}Hz",
+            source_sample_rate, rate
+        ));
+    }
+
+    let processed = resample.is_some() || settings.downmix.is_some() || settings.deemphasis;
+
+    if source_bits_per_sample > settings.target_bits_per_sample
+        && settings.padded_source
+        && !processed
+    {
+        steps.push(format!(
+            "drop {} zero padded bits",
+            source_bits_per_sample - settings.target_bits_per_sample
+        ));
+    } else if processed || source_bits_per_sample > settings.target_bits_per_sample {
+        steps.push(format!(
+            "TPDF dither to {} bit",
+            settings.target_bits_per_sample
+        ));
+    }
+
+    format!(
+        "red_oxide native ({}) to s{}le PCM",
+        steps.join(", "),
+        settings.target_bits_per_sample
+    )
+}
+
+/// Decodes the given FLAC in-process (de-emphasizing, downmixing and resampling to the target
+/// sample rate if needed) and sends the audio in chunks of raw PCM with the target bit depth.
+/// Decoding stops early once the receiver is dropped.
+pub fn spawn_native_decoder(path: PathBuf, settings: DecodeSettings) -> NativeDecoder {
+    let (sender, receiver) = mpsc::channel(PCM_CHANNEL_CAPACITY);
+
+    let handle = tokio::task::spawn_blocking(move || {
+        let DecodeSettings {
+            target_sample_rate,
+            target_bits_per_sample,
+            downmix,
+            deemphasis,
+            padded_source,
+        } = settings;
+
+        let mut reader = FlacReader::open(&path)?;
+        let info = reader.streaminfo();
+
+        let channels = info.channels as usize;
+        let bits_per_sample = info.bits_per_sample;
+
+        let mut dither = TpdfDither::new(DITHER_SEED);
+        let output_channels = if downmix.is_some() { 2 } else { channels };
+
+        let mut deemphasis_filter =
+            deemphasis.then(|| DeemphasisFilter::new(info.sample_rate, channels));
+
+        let mut resampler = match target_sample_rate {
+            Some(rate) if rate != info.sample_rate => Some(StreamResampler::new(
+                info.sample_rate,
+                rate,
+                output_channels,
+            )?),
+            _ => None,
+        };
+
+        let mut blocks = reader.blocks();
+        let mut buffer = Vec::new();
+
+        while let Some(block) = blocks.read_next_or_eof(buffer)? {
+            let pcm = if resampler.is_some() || downmix.is_some() || deemphasis_filter.is_some() {
+                let scale = 1.0 / (1i64 << (bits_per_sample - 1)) as f64;
+                let mut input = (0..channels)
+                    .map(|channel| {
+                        block
+                            .channel(channel as u32)
+                            .iter()
+                            .map(|sample| *sample as f64 * scale)
+                            .collect::<Vec<f64>>()
+                    })
+                    .collect::<Vec<Vec<f64>>>();
+
+                if let Some(deemphasis_filter) = deemphasis_filter.as_mut() {
+                    deemphasis_filter.apply(&mut input);
+                }
+
+                if let Some(downmix) = &downmix {
+                    input = downmix.apply(&input);
+                }
+
+                if let Some(resampler) = resampler.as_mut() {
+                    input = resampler.push(input)?;
+                }
+
+                float_to_pcm(&input, target_bits_per_sample, &mut dither)
+            } else {
+                integer_to_pcm(
+                    &channels_of(&block),
+                    bits_per_sample,
+                    target_bits_per_sample,
+                    (!padded_source).then_some(&mut dither),
+                )
+            };
+
+            buffer = block.into_buffer();
+
+            if !pcm.is_empty() && sender.blocking_send(pcm).is_err() {
+                // The fan-out stopped listening, nobody needs the rest of the audio
+                return Ok(());
+            }
+        }
+
+        if let Some(resampler) = resampler {
+            let pcm = float_to_pcm(&resampler.finish()?, target_bits_per_sample, &mut dither);
+
+            if !pcm.is_empty() {
+                let _ = sender.blocking_send(pcm);
+            }
+        }
+
+        Ok(())
+    });
+
+    NativeDecoder { handle, receiver }
+}
+
+/// Decodes the given FLAC once and sends the samples of every range `start..end` unchanged as raw
+/// PCM to the receiver of that range. The PCM has to have at least the bit depth of the source so
+/// every sample is kept exactly. Used to cut all tracks out of an image in one pass, claxon can
+/// not seek, so the ranges have to be in order and must not overlap.
+pub fn spawn_range_decoder(
+    path: PathBuf,
+    ranges: Vec<(u64, u64)>,
+    target_bits_per_sample: u32,
+) -> RangeDecoder {
+    let (senders, receivers): (Vec<_>, Vec<_>) = ranges
+        .iter()
+        .map(|_| mpsc::channel(PCM_CHANNEL_CAPACITY))
+        .unzip();
+
+    let handle = tokio::task::spawn_blocking(move || {
+        let mut reader = FlacReader::open(&path)?;
+        let info = reader.streaminfo();
+
+        let channels = info.channels as usize;
+        let frame_size = channels * (target_bits_per_sample / 8) as usize;
+
+        // The sender of a range is dropped once its end is reached, which ends its track
+        let mut pending = senders.into_iter().zip(ranges).peekable();
+
+        let mut blocks = reader.blocks();
+        let mut buffer = Vec::new();
+        let mut position = 0;
+
+        while let Some(block) = blocks.read_next_or_eof(buffer)? {
+            let block_start = position;
+            position += block.duration() as u64;
+
+            let mut pcm = None;
+
+            while let Some((sender, (start, end))) = pending.peek() {
+                let (start, end) = (*start, *end);
+
+                if start >= position {
+                    break;
+                }
+
+                let first = start.saturating_sub(block_start) as usize;
+                let last = (end.min(position) - block_start) as usize;
+
+                let pcm = pcm.get_or_insert_with(|| {
+                    integer_to_pcm(
+                        &channels_of(&block),
+                        info.bits_per_sample,
+                        target_bits_per_sample,
+                        None,
+                    )
+                });
+
+                // The receiver is gone when its track failed, the split is abandoned then
+                if sender
+                    .blocking_send(pcm[first * frame_size..last * frame_size].to_vec())
+                    .is_err()
+                {
+                    return Ok(());
+                }
+
+                if end > position {
+                    break;
+                }
+
+                pending.next();
+            }
+
+            if pending.peek().is_none() {
+                break;
+            }
+
+            buffer = block.into_buffer();
+        }
+
+        Ok(())
+    });
+
+    RangeDecoder { handle, receivers }
+}
+
+fn channels_of(block: &Block) -> Vec<&[i32]> {
+    (0..block.channels())
+        .map(|channel| block.channel(channel))
+        .collect()
+}
+
+/// Converts the samples of every channel to interleaved PCM of the target bit depth. Without
+/// dither a lower bit depth is reached by dropping the lowest bits, which is exact for padded
+/// sources only.
+fn integer_to_pcm(
+    channels: &[&[i32]],
+    bits_per_sample: u32,
+    target_bits_per_sample: u32,
+    mut dither: Option<&mut TpdfDither>,
+) -> Vec<u8> {
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    let bytes_per_sample = (target_bits_per_sample / 8) as usize;
+    let mut pcm = Vec::with_capacity(frames * channels.len() * bytes_per_sample);
+
+    for frame in 0..frames {
+        for channel in channels {
+            let sample = channel[frame];
+
+            let sample = if bits_per_sample > target_bits_per_sample {
+                let shift = bits_per_sample - target_bits_per_sample;
+
+                match dither.as_deref_mut() {
+                    Some(dither) => quantize(
+                        sample as f64 / (1i64 << shift) as f64,
+                        target_bits_per_sample,
+                        dither,
+                    ),
+                    None => sample >> shift,
+                }
+            } else {
+                sample << (target_bits_per_sample - bits_per_sample)
+            };
+
+            pcm.extend_from_slice(&sample.to_le_bytes()[..bytes_per_sample]);
+        }
+    }
+
+    pcm
+}
+
+fn float_to_pcm(
+    channels: &[Vec<f64>],
+    target_bits_per_sample: u32,
+    dither: &mut TpdfDither,
+) -> Vec<u8> {
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    let bytes_per_sample = (target_bits_per_sample / 8) as usize;
+    let scale = (1i64 << (target_bits_per_sample - 1)) as f64;
+    let mut pcm = Vec::with_capacity(frames * channels.len() * bytes_per_sample);
+
+    for frame in 0..frames {
+        for channel in channels {
+            let sample = quantize(channel[frame] * scale, target_bits_per_sample, dither);
+            pcm.extend_from_slice(&sample.to_le_bytes()[..bytes_per_sample]);
+        }
+    }
+
+    pcm
+}
+
+/// Rounds a sample given in units of the target bit depth after adding the dither noise. The
+/// little endian bytes of the result truncated to the bit depth are the PCM sample.
+fn quantize(sample: f64, target_bits_per_sample: u32, dither: &mut TpdfDither) -> i32 {
+    let max = ((1i64 << (target_bits_per_sample - 1)) - 1) as f64;
+
+    (sample + dither.next()).round().clamp(-max - 1.0, max) as i32
+}
+
+/// Triangular (TPDF) dither noise of +-1 LSB, generated by a xorshift so no extra dependency is
+/// needed for it.
+struct TpdfDither {
+    state: u64,
+}
+
+impl TpdfDither {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// First order shelving filter undoing the 50/15µs pre-emphasis of a CD, made from the analog
+/// filter by the bilinear transform with both corner frequencies prewarped.
+struct DeemphasisFilter {
+    b0: f64,
+    b1: f64,
+    a1: f64,
+    /// The previous input and output sample of every channel
+    state: Vec<(f64, f64)>,
+}
+
+impl DeemphasisFilter {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        let k = 2.0 * sample_rate as f64;
+        let prewarp = |time_constant: f64| 1.0 / (k * (1.0 / (k * time_constant)).tan());
+
+        let pole = prewarp(EMPHASIS_POLE_TIME_CONSTANT);
+        let zero = prewarp(EMPHASIS_ZERO_TIME_CONSTANT);
+        let norm = 1.0 + k * pole;
+
+        Self {
+            b0: (1.0 + k * zero) / norm,
+            b1: (1.0 - k * zero) / norm,
+            a1: (1.0 - k * pole) / norm,
+            state: vec![(0.0, 0.0); channels],
+        }
+    }
+
+    fn apply(&mut self, channels: &mut [Vec<f64>]) {
+        for (channel, (previous_input, previous_output)) in
+            channels.iter_mut().zip(self.state.iter_mut())
+        {
+            for sample in channel.iter_mut() {
+                let output =
+                    self.b0 * *sample + self.b1 * *previous_input - self.a1 * *previous_output;
+
+                *previous_input = *sample;
+                *previous_output = output;
+                *sample = output;
+            }
+        }
+    }
+}
+
+/// Feeds blocks of any size through the fixed chunk size resampler and removes its delay, so
+/// the output lines up with the input and has exactly the resampled length.
+struct StreamResampler {
+    resampler: FftFixedIn<f64>,
+    pending: Vec<Vec<f64>>,
+    delay_left: usize,
+    input_frames: u64,
+    output_frames: u64,
+    from_sample_rate: u32,
+    to_sample_rate: u32,
+}
+
+impl StreamResampler {
+    fn new(from_sample_rate: u32, to_sample_rate: u32, channels: usize) -> anyhow::Result<Self> {
+        let resampler = FftFixedIn::new(
+            from_sample_rate as usize,
+            to_sample_rate as usize,
+            RESAMPLER_CHUNK_SIZE,
+            2,
+            channels,
+        )?;
+
+        Ok(Self {
+            delay_left: resampler.output_delay(),
+            resampler,
+            pending: vec![Vec::new(); channels],
+            input_frames: 0,
+            output_frames: 0,
+            from_sample_rate,
+            to_sample_rate,
+        })
+    }
+
+    fn push(&mut self, input: Vec<Vec<f64>>) -> anyhow::Result<Vec<Vec<f64>>> {
+        self.input_frames += input.first().map_or(0, |channel| channel.len()) as u64;
+
+        for (pending, channel) in self.pending.iter_mut().zip(input) {
+            pending.extend(channel);
+        }
+
+        let mut output = vec![Vec::new(); self.pending.len()];
+
+        while self.pending[0].len() >= self.resampler.input_frames_next() {
+            let frames = self.resampler.input_frames_next();
+            let chunk = self
+                .pending
+                .iter_mut()
+                .map(|pending| pending.drain(..frames).collect::<Vec<f64>>())
+                .collect::<Vec<Vec<f64>>>();
+
+            let resampled = self.resampler.process(&chunk, None)?;
+            self.append_output(resampled, &mut output, u64::MAX);
+        }
+
+        Ok(output)
+    }
+
+    fn finish(mut self) -> anyhow::Result<Vec<Vec<f64>>> {
+        let expected =
+            self.input_frames * self.to_sample_rate as u64 / self.from_sample_rate as u64;
+
+        let mut output = vec![Vec::new(); self.pending.len()];
+
+        let pending = std::mem::take(&mut self.pending);
+        let resampled = self.resampler.process_partial(Some(&pending), None)?;
+        self.append_output(resampled, &mut output, expected);
+
+        // The resampler still holds its delay worth of audio, flush it out with silence
+        while self.output_frames < expected {
+            let resampled = self.resampler.process_partial(None::<&[Vec<f64>]>, None)?;
+            self.append_output(resampled, &mut output, expected);
+        }
+
+        Ok(output)
+    }
+
+    fn append_output(&mut self, resampled: Vec<Vec<f64>>, output: &mut [Vec<f64>], limit: u64) {
+        let frames = resampled.first().map_or(0, |channel| channel.len());
+        let skip = self.delay_left.min(frames);
+        self.delay_left -= skip;
+
+        let take = ((frames - skip) as u64).min(limit.saturating_sub(self.output_frames)) as usize;
+        self.output_frames += take as u64;
+
+        for (output, channel) in output.iter_mut().zip(resampled) {
+            output.extend_from_slice(&channel[skip..skip + take]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(target_sample_rate: Option<u32>, target_bits_per_sample: u32) -> DecodeSettings {
+        DecodeSettings {
+            target_sample_rate,
+            target_bits_per_sample,
+            downmix: None,
+            deemphasis: false,
+            padded_source: false,
+        }
+    }
+
+    #[test]
+    fn dither_stays_within_one_lsb() {
+        let mut dither = TpdfDither::new(DITHER_SEED);
+        let noise = (0..100_000).map(|_| dither.next()).collect::<Vec<f64>>();
+
+        assert!(noise.iter().all(|n| n.abs() < 1.0));
+
+        let mean = noise.iter().sum::<f64>() / noise.len() as f64;
+        assert!(mean.abs() < 0.01);
+    }
+
+    #[test]
+    fn dither_is_reproducible() {
+        let mut first = TpdfDither::new(DITHER_SEED);
+        let mut second = TpdfDither::new(DITHER_SEED);
+
+        for _ in 0..1000 {
+            assert_eq!(first.next(), second.next());
+        }
+    }
+
+    #[test]
+    fn quantize_clamps_to_the_bit_depth() {
+        let mut dither = TpdfDither::new(DITHER_SEED);
+
+        assert_eq!(quantize(1e9, 16, &mut dither), i16::MAX as i32);
+        assert_eq!(quantize(-1e9, 16, &mut dither), i16::MIN as i32);
+        assert_eq!(quantize(1e9, 24, &mut dither), (1 << 23) - 1);
+
+        let rounded = quantize(1000.0, 16, &mut dither);
+        assert!((999..=1001).contains(&rounded));
+    }
+
+    #[test]
+    fn float_to_pcm_interleaves_little_endian() {
+        let mut dither = TpdfDither::new(DITHER_SEED);
+        let channels = vec![vec![2.0, 0.0], vec![-2.0, 0.0]];
+
+        let pcm = float_to_pcm(&channels, 24, &mut dither);
+
+        assert_eq!(pcm.len(), 2 * 2 * 3);
+        assert_eq!(&pcm[0..3], &[0xff, 0xff, 0x7f]);
+        assert_eq!(&pcm[3..6], &[0x00, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn deemphasis_keeps_dc_and_cuts_treble() {
+        let mut filter = DeemphasisFilter::new(44100, 2);
+        let mut channels = vec![
+            vec![1.0; 2000],
+            (0..2000).map(|i| [1.0, -1.0][i % 2]).collect(),
+        ];
+
+        filter.apply(&mut channels);
+
+        assert!((channels[0][1999] - 1.0).abs() < 1e-6);
+
+        // 50/15µs de-emphasis ends up around -12dB at the Nyquist frequency of 44.1kHz
+        let nyquist_gain = channels[1][1999].abs();
+        assert!(nyquist_gain > 0.2 && nyquist_gain < 0.3);
+    }
+
+    #[test]
+    fn resampler_output_has_exact_length() {
+        let mut resampler = StreamResampler::new(96000, 44100, 1).unwrap();
+        let mut output_frames = 0;
+
+        for block_size in [4096, 1152, 4608, 100, 9000] {
+            output_frames += resampler.push(vec![vec![0.25; block_size]]).unwrap()[0].len();
+        }
+
+        output_frames += resampler.finish().unwrap()[0].len();
+
+        assert_eq!(output_frames, 18956 * 44100 / 96000);
+    }
+
+    #[test]
+    fn padded_source_is_shifted_exactly() {
+        let describe = |padded_source| {
+            let settings = DecodeSettings {
+                padded_source,
+                ..settings(Some(44100), 16)
+            };
+
+            describe_native_pipeline(44100, 24, &settings)
+        };
+
+        assert_eq!(
+            describe(true),
+            "red_oxide native (claxon decode, drop 8 zero padded bits) to s16le PCM"
+        );
+        assert_eq!(
+            describe(false),
+            "red_oxide native (claxon decode, TPDF dither to 16 bit) to s16le PCM"
+        );
+    }
+
+    #[test]
+    fn padded_samples_lose_only_their_zero_bits() {
+        let left = [0x123400, -0x800000, 0x7fff00];
+        let right = [-0x000100, 0, 0x000500];
+
+        let pcm = integer_to_pcm(&[&left, &right], 24, 16, None);
+
+        let samples = pcm
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<i16>>();
+
+        assert_eq!(samples, vec![0x1234, -1, -0x8000, 0, 0x7fff, 0x0005]);
+    }
+
+    #[test]
+    fn dithered_samples_are_not_shifted_exactly() {
+        let silence = [0; 64];
+        let mut dither = TpdfDither::new(DITHER_SEED);
+
+        let exact = integer_to_pcm(&[&silence], 24, 16, None);
+        let dithered = integer_to_pcm(&[&silence], 24, 16, Some(&mut dither));
+
+        assert!(exact.iter().all(|byte| *byte == 0));
+        assert!(dithered.iter().any(|byte| *byte != 0));
+    }
+
+    #[test]
+    fn describes_only_the_steps_taken() {
+        assert_eq!(
+            describe_native_pipeline(44100, 16, &settings(None, 16)),
+            "red_oxide native (claxon decode) to s16le PCM"
+        );
+        assert_eq!(
+            describe_native_pipeline(96000, 24, &settings(Some(48000), 16)),
+            "red_oxide native (claxon decode, rubato FFT resample 96000Hz to 48000Hz, TPDF dither to 16 bit) to s16le PCM"
+        );
+        assert_eq!(
+            describe_native_pipeline(44100, 24, &settings(Some(44100), 16)),
+            "red_oxide native (claxon decode, TPDF dither to 16 bit) to s16le PCM"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use claxon::FlacReader;
+
+use crate::fs::util::get_all_files_with_extension;
+use crate::transcode::error::TranscodeError::UnknownSampleRateError;
+
+/// The highest sample rate transcodes are made at, anything above is downsampled.
+pub const MAX_TARGET_SAMPLE_RATE: u32 = 48000;
+
+const MIN_SOURCE_SAMPLE_RATE: u32 = 8000;
+const MAX_SOURCE_SAMPLE_RATE: u32 = 768000;
+
+/// The sample rates of MPEG-1, MPEG-2 and MPEG-2.5 audio.
+const MP3_SAMPLE_RATES: [u32; 9] = [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+/// The sample rate every track of a release is transcoded to, decided once for the whole
+/// release so mixed-rate releases do not end up with tracks at different rates.
+#[derive(Debug, Clone)]
+pub struct SampleRatePlan {
+    /// How many tracks the release has at each sample rate
+    pub source_sample_rates: BTreeMap<u32, usize>,
+    pub target_sample_rate: u32,
+    pub reason: String,
+}
+
+impl SampleRatePlan {
+    pub fn new(source_sample_rates: BTreeMap<u32, usize>) -> Self {
+        let single_rate = match source_sample_rates.len() {
+            1 => source_sample_rates.keys().next().copied(),
+            _ => None,
+        };
+
+        // Low rates like 32kHz or 22.05kHz are kept, upsampling them would not add anything
+        if let Some(rate) = single_rate.filter(|rate| *rate <= MAX_TARGET_SAMPLE_RATE) {
+            return Self {
+                source_sample_rates,
+                target_sample_rate: rate,
+                reason: format!("every track is at {}, which is kept", format_rate(rate)),
+            };
+        }
+
+        let mut cd_family_tracks = 0;
+        let mut dvd_family_tracks = 0;
+
+        for (rate, tracks) in &source_sample_rates {
+            if is_cd_family(*rate) {
+                cd_family_tracks += tracks;
+            } else {
+                dvd_family_tracks += tracks;
+            }
+        }
+
+        let (target_sample_rate, reason) = match (cd_family_tracks, dvd_family_tracks) {
+            (_, 0) => (44100, "every track is a multiple of 44.1kHz".to_string()),
+            (0, _) => (48000, "no track is a multiple of 44.1kHz".to_string()),
+            (cd, dvd) if cd >= dvd => (
+                44100,
+                format!(
+                    "mixed release, {} of {} tracks are a multiple of 44.1kHz",
+                    cd,
+                    cd + dvd
+                ),
+            ),
+            (cd, dvd) => (
+                48000,
+                format!(
+                    "mixed release, {} of {} tracks are not a multiple of 44.1kHz",
+                    dvd,
+                    cd + dvd
+                ),
+            ),
+        };
+
+        Self {
+            source_sample_rates,
+            target_sample_rate,
+            reason,
+        }
+    }
+
+    /// If any track is above the highest target sample rate.
+    pub fn needs_downsampling(&self) -> bool {
+        self.source_sample_rates
+            .keys()
+            .any(|rate| *rate > MAX_TARGET_SAMPLE_RATE)
+    }
+
+    /// The rate MP3s of the release are encoded at, MPEG audio only supports a few rates so odd
+    /// targets (e.g. 37.8kHz) go to the nearest of those.
+    pub fn mp3_target_sample_rate(&self) -> u32 {
+        MP3_SAMPLE_RATES
+            .into_iter()
+            .min_by_key(|rate| rate.abs_diff(self.target_sample_rate))
+            .unwrap()
+    }
+}
+
+impl fmt::Display for SampleRatePlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sources = self
+            .source_sample_rates
+            .iter()
+            .map(|(rate, tracks)| format!("{} ({} track(s))", format_rate(*rate), tracks))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(
+            f,
+            "{} to {}, {}",
+            sources,
+            format_rate(self.target_sample_rate),
+            self.reason
+        )
+    }
+}
+
+/// Reads the sample rate of every track and plans the target sample rate of the release.
+pub async fn plan_sample_rate(flac_dir: &PathBuf) -> anyhow::Result<SampleRatePlan> {
+    let paths = get_all_files_with_extension(flac_dir, ".flac").await?;
+
+    let mut source_sample_rates = BTreeMap::new();
+
+    for path in paths {
+        let path_cloned = path.clone();
+        let reader = tokio::task::spawn_blocking(move || FlacReader::open(path_cloned)).await??;
+        let sample_rate = reader.streaminfo().sample_rate;
+
+        if !(MIN_SOURCE_SAMPLE_RATE..=MAX_SOURCE_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(UnknownSampleRateError(path, sample_rate).into());
+        }
+
+        *source_sample_rates.entry(sample_rate).or_insert(0) += 1;
+    }
+
+    Ok(SampleRatePlan::new(source_sample_rates))
+}
+
+/// Rates of the CD family (11.025kHz, 22.05kHz, 88.2kHz, ...) go to 44.1kHz, every other rate
+/// (32kHz, 96kHz, 384kHz, but also odd ones) to 48kHz.
+fn is_cd_family(sample_rate: u32) -> bool {
+    sample_rate.is_multiple_of(11025)
+}
+
+fn format_rate(sample_rate: u32) -> String {
+    format!("{}kHz", sample_rate as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(rates: &[(u32, usize)]) -> SampleRatePlan {
+        SampleRatePlan::new(rates.iter().copied().collect())
+    }
+
+    #[test]
+    fn keeps_single_low_rate() {
+        let plan = plan(&[(32000, 5)]);
+
+        assert_eq!(plan.target_sample_rate, 32000);
+        assert!(!plan.needs_downsampling());
+    }
+
+    #[test]
+    fn picks_one_rate_for_mixed_release() {
+        let plan = plan(&[(44100, 4), (48000, 3), (96000, 2)]);
+
+        assert_eq!(plan.target_sample_rate, 48000);
+        assert_eq!(plan.mp3_target_sample_rate(), 48000);
+    }
+
+    #[test]
+    fn downsamples_to_majority_family() {
+        assert_eq!(plan(&[(88200, 3), (96000, 2)]).target_sample_rate, 44100);
+        assert_eq!(plan(&[(88200, 3), (48000, 10)]).target_sample_rate, 48000);
+        assert_eq!(plan(&[(384000, 1)]).target_sample_rate, 48000);
+        assert_eq!(plan(&[(176400, 1)]).target_sample_rate, 44100);
+    }
+
+    #[test]
+    fn maps_mp3_to_nearest_mpeg_rate() {
+        assert_eq!(plan(&[(37800, 1)]).mp3_target_sample_rate(), 32000);
+        assert_eq!(plan(&[(22050, 1)]).mp3_target_sample_rate(), 22050);
+        assert_eq!(plan(&[(8000, 1)]).mp3_target_sample_rate(), 8000);
+        assert_eq!(plan(&[(192000, 1)]).mp3_target_sample_rate(), 48000);
+    }
+}
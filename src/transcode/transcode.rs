@@ -1,265 +1,826 @@
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::Arc;
 
 use claxon::FlacReader;
 use console::Term;
+use futures::future::join_all;
 use indicatif::ProgressBar;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
 use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 
 use crate::fs::util::get_all_files_with_extension;
 use crate::redacted::models::ReleaseType;
-use crate::redacted::models::ReleaseType::{Flac, Mp3320};
+use crate::redacted::models::ReleaseType::{Aac256, Flac, Mp3320, Mp3V2, Opus};
 use crate::transcode::error::TranscodeError;
-use crate::transcode::error::TranscodeError::{Invalid24BitFlac, OutputDirectoryExist};
+use crate::transcode::error::TranscodeError::{
+    EncoderFailed, Invalid24BitFlac, OutputDirectoryExist, PartialOutputExist, TracksFailed,
+};
 use ReleaseType::{Flac24, Mp3V0};
 
-use crate::ext_deps::util::{get_flac_executable, get_lame_executable, get_sox_executable};
-use crate::transcode::util;
-use crate::ERROR;
+use crate::transcode::backend::{EncoderBackend, SourceInfo};
+use crate::transcode::downmix::Downmix;
+use crate::transcode::native::{DecodeSettings, NativeDecoder, PcmFormat};
+use crate::transcode::sample_rate::SampleRatePlan;
+use crate::transcode::{lame, native, util, verify};
+use crate::{ERROR, INFO, WARNING};
+
+const FAN_OUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Where the decoded audio for the encoders comes from.
+enum Decoder {
+    External(&'static str, Command),
+    Native {
+        path: PathBuf,
+        settings: DecodeSettings,
+    },
+}
+
+/// Settings of a transcode which apply to every track of the release.
+pub struct TranscodeOptions {
+    pub torrent_id: i64,
+    /// Keeps outputs of an earlier run which are already complete
+    pub resume: bool,
+    /// Decodes with the decoder of the backend instead of the built-in one
+    pub external_decoder: bool,
+    /// Mixes multichannel sources down to stereo instead of rejecting them
+    pub downmix: bool,
+    /// The rate every track is transcoded at
+    pub sample_rate_plan: SampleRatePlan,
+    /// Tracks mastered with pre-emphasis, which is undone while decoding
+    pub emphasized_tracks: HashSet<PathBuf>,
+    /// The source is 16bit audio padded to 24bit, so it is reduced to 16bit without dither
+    pub padded_source: bool,
+    pub backend: Arc<dyn EncoderBackend>,
+}
+
+pub struct TranscodeTarget {
+    pub format: ReleaseType,
+    pub folder_name: String,
+    pub pb_format: ProgressBar,
+}
+
+pub struct TranscodedRelease {
+    pub path: PathBuf,
+    pub command: String,
+    /// Encoder settings read back from the transcoded files, if the format records them
+    pub encoder_settings: Option<String>,
+}
+
+pub struct TrackOutput {
+    pub path: PathBuf,
+    pub command: String,
+    pub result: anyhow::Result<()>,
+    pub reused: bool,
+}
 
+/// Transcodes the release to every target format. Each format succeeds or fails on its own, the
+/// result of every target is returned in the order of the given targets.
 pub async fn transcode_release(
     flac_dir: &PathBuf,
-    output_dir: &mut PathBuf,
-    folder_name: String,
-    format: ReleaseType,
+    output_dir: &Path,
+    targets: Vec<TranscodeTarget>,
+    options: Arc<TranscodeOptions>,
     term: Arc<Term>,
-    torrent_id: i64,
-    pb_format: ProgressBar,
     pb_main: ProgressBar,
     semaphore_clone: Arc<Semaphore>,
-) -> anyhow::Result<(PathBuf, String)> {
+) -> anyhow::Result<Vec<(ReleaseType, anyhow::Result<TranscodedRelease>)>> {
     let needs_resample = util::is_24_bit_flac(flac_dir).await?;
 
-    if format == Flac && !needs_resample {
-        term.write_line(&format!(
-            "{} some file(s) of torrent {} were incorrectly marked as 24bit.",
-            ERROR, torrent_id
-        ))?;
-        return Err(Invalid24BitFlac.into());
-    }
+    let mut failures: Vec<Option<anyhow::Error>> = targets.iter().map(|_| None).collect();
 
-    output_dir.push(folder_name);
+    let mut active = Vec::new();
+    let mut final_output_dirs = Vec::new();
+    let mut outputs = Vec::new();
+
+    for (index, target) in targets.iter().enumerate() {
+        if target.format == Flac && !needs_resample {
+            term.write_line(&format!(
+                "{} some file(s) of torrent {} were incorrectly marked as 24bit.",
+                ERROR, options.torrent_id
+            ))?;
+            failures[index] = Some(Invalid24BitFlac.into());
+            continue;
+        }
 
-    let output_dir_metadata = fs::metadata(&output_dir).await;
+        let format_output_dir = output_dir.join(&target.folder_name);
+
+        let output_dir_metadata = fs::metadata(&format_output_dir).await;
+
+        // Everything is written to a staging directory first which only gets renamed to the
+        // final name once the whole release succeeded, so a half-written release never shows
+        // up under the final name. Interrupted runs keep it for `--resume` to pick up again.
+        let staging_dir = output_dir.join(format!(".{}.partial", target.folder_name));
+        let staging_exists = fs::try_exists(&staging_dir).await?;
+
+        if let Ok(output_dir_metadata) = output_dir_metadata.as_ref() {
+            if output_dir_metadata.is_dir() {
+                if !options.resume {
+                    failures[index] = Some(OutputDirectoryExist(format_output_dir).into());
+                    continue;
+                }
+
+                if staging_exists {
+                    term.write_line(&format!(
+                        "{} {} output of torrent {} exists both finished and partial at \"{}\", remove one of them to resume",
+                        WARNING,
+                        target.format,
+                        options.torrent_id,
+                        staging_dir.display()
+                    ))?;
+                    failures[index] = Some(PartialOutputExist(staging_dir).into());
+                    continue;
+                }
+
+                // The existing output is picked up again through the staging directory so a
+                // resumed run is just as atomic as a fresh one
+                fs::rename(&format_output_dir, &staging_dir).await?;
+            }
+        }
 
-    if let Some(output_dir_metadata) = output_dir_metadata.as_ref().ok() {
-        if output_dir_metadata.is_dir() {
-            return Err(OutputDirectoryExist(output_dir.to_owned()).into());
+        // Partial output may hold hours of encoding, it is never thrown away without asking
+        if staging_exists && !options.resume {
+            term.write_line(&format!(
+                "{} Partial {} output of torrent {} is left from an earlier run at \"{}\", continue it with --resume or remove it",
+                WARNING,
+                target.format,
+                options.torrent_id,
+                staging_dir.display()
+            ))?;
+            failures[index] = Some(PartialOutputExist(staging_dir).into());
+            continue;
         }
-    }
 
-    fs::create_dir_all(&output_dir).await?;
+        fs::create_dir_all(&staging_dir).await?;
+
+        active.push(index);
+        final_output_dirs.push(format_output_dir);
+        outputs.push((target.format, staging_dir));
+    }
 
-    let paths = get_all_files_with_extension(&flac_dir, ".flac").await?;
+    let paths = get_all_files_with_extension(flac_dir, ".flac").await?;
 
-    pb_format.set_message(format!("{} transcoding", format));
+    for (index, target) in targets.iter().enumerate() {
+        if failures[index].is_some() {
+            target
+                .pb_format
+                .abandon_with_message(format!("{} skipped", target.format));
+        } else {
+            target
+                .pb_format
+                .set_message(format!("{} transcoding", target.format));
+        }
+    }
 
-    let mut command = "".to_string();
+    let outputs = Arc::new(outputs);
 
     let mut handles = vec![];
-    for path in paths {
-        let pb = pb_format.clone();
-        let output_dir = output_dir.clone();
+    for path in paths.clone() {
+        let outputs = outputs.clone();
+        let pbs_format = active
+            .iter()
+            .map(|index| targets[*index].pb_format.clone())
+            .collect::<Vec<ProgressBar>>();
         let pb_main = pb_main.clone();
         let semaphore_clone = semaphore_clone.clone();
+        let flac_dir = flac_dir.clone();
+        let options = options.clone();
         handles.push(tokio::spawn(async move {
-            let _permit = semaphore_clone.acquire().await?;
-            let (output_path, command) = transcode(&path, &output_dir, format).await?;
+            if outputs.is_empty() {
+                return Ok(vec![]);
+            }
 
-            if format != Flac {
-                crate::tags::util::copy_tags_to_mp3(&path, &output_path).await?;
+            let _permit = semaphore_clone.acquire().await?;
+            let mut results = transcode(&flac_dir, &path, &outputs, &options).await?;
+
+            for ((format, _), output) in outputs.iter().zip(results.iter_mut()) {
+                if output.reused || output.result.is_err() {
+                    continue;
+                }
+
+                let tagged = match format {
+                    Flac | Flac24 => {
+                        crate::tags::util::copy_tags_to_flac(&path, &output.path).await
+                    }
+                    Mp3V0 | Mp3320 | Mp3V2 => {
+                        crate::tags::util::copy_tags_to_mp3(&path, &output.path).await
+                    }
+                    Aac256 => crate::tags::util::copy_tags_to_m4a(&path, &output.path).await,
+                    Opus => crate::tags::util::copy_tags_to_opus(&path, &output.path).await,
+                };
+
+                if let Err(e) = tagged {
+                    output.result = Err(e);
+                }
             }
 
-            pb.inc(1);
-            pb_main.inc(1);
+            for pb in pbs_format {
+                pb.inc(1);
+                pb_main.inc(1);
+            }
 
-            Ok::<String, anyhow::Error>(command)
+            Ok::<Vec<TrackOutput>, anyhow::Error>(results)
         }));
     }
 
-    for handle in handles {
-        command = handle.await??;
+    let mut commands = vec!["".to_string(); active.len()];
+    let mut failed_tracks = vec![0; active.len()];
+    let mut reused_tracks = vec![0; active.len()];
+    let mut tracks_to_verify = vec![Vec::new(); active.len()];
+    let mut transcoded_tracks = vec![Vec::new(); active.len()];
+
+    for (path, handle) in paths.iter().zip(handles) {
+        let track_results = match handle.await? {
+            Ok(track_results) => track_results,
+            Err(e) => {
+                term.write_line(&format!(
+                    "{} Failed to transcode \"{}\": {}",
+                    ERROR,
+                    path.display(),
+                    e
+                ))?;
+
+                failed_tracks.iter_mut().for_each(|count| *count += 1);
+                continue;
+            }
+        };
+
+        for (position, output) in track_results.into_iter().enumerate() {
+            if output.reused {
+                reused_tracks[position] += 1;
+            }
+
+            match output.result {
+                Ok(()) => {
+                    commands[position] = output.command;
+                    transcoded_tracks[position].push((path.clone(), output.path.clone()));
+
+                    // Reused outputs were already verified when they were picked up again
+                    if !output.reused {
+                        tracks_to_verify[position].push((path.clone(), output.path));
+                    }
+                }
+                Err(e) => {
+                    failed_tracks[position] += 1;
+                    term.write_line(&format!(
+                        "{} Failed to transcode \"{}\" to {}: {}",
+                        ERROR,
+                        path.display(),
+                        targets[active[position]].format,
+                        e
+                    ))?;
+                }
+            }
+        }
     }
 
-    pb_format.finish_with_message(format!("{} transcoding done", format));
+    let mut successes: Vec<Option<TranscodedRelease>> = targets.iter().map(|_| None).collect();
+
+    for (position, ((index, (_, staging_dir)), final_output_dir)) in active
+        .iter()
+        .zip(outputs.iter())
+        .zip(final_output_dirs)
+        .enumerate()
+    {
+        let target = &targets[*index];
+
+        if reused_tracks[position] > 0 {
+            term.write_line(&format!(
+                "{} Resumed {}, kept {} already transcoded track(s)",
+                INFO, target.format, reused_tracks[position]
+            ))?;
+        }
+
+        if failed_tracks[position] > 0 {
+            target
+                .pb_format
+                .abandon_with_message(format!("{} transcoding failed", target.format));
+            failures[*index] = Some(TracksFailed(target.format, failed_tracks[position]).into());
 
-    Ok((output_dir.to_owned(), command))
+            remove_failed_staging(staging_dir, &options).await?;
+            continue;
+        }
+
+        target
+            .pb_format
+            .set_message(format!("{} verifying", target.format));
+
+        let finalized = async {
+            // A format that does not verify never reaches torrent creation
+            verify::verify_tracks(&tracks_to_verify[position], semaphore_clone.clone()).await?;
+
+            let encoder_settings = match target.format {
+                Mp3V0 | Mp3320 | Mp3V2 if options.backend.writes_lame_header(target.format) => {
+                    verify_lame_headers(
+                        &transcoded_tracks[position],
+                        target.format,
+                        &options.sample_rate_plan,
+                    )
+                    .await?
+                }
+                _ => None,
+            };
+
+            util::copy_other_allowed_files(flac_dir, flac_dir, staging_dir).await?;
+
+            fs::rename(staging_dir, &final_output_dir).await?;
+
+            Ok::<Option<String>, anyhow::Error>(encoder_settings)
+        }
+        .await;
+
+        let encoder_settings = match finalized {
+            Ok(encoder_settings) => encoder_settings,
+            Err(e) => {
+                target
+                    .pb_format
+                    .abandon_with_message(format!("{} transcoding failed", target.format));
+                failures[*index] = Some(e);
+
+                remove_failed_staging(staging_dir, &options).await?;
+                continue;
+            }
+        };
+
+        target
+            .pb_format
+            .finish_with_message(format!("{} transcoding done", target.format));
+
+        successes[*index] = Some(TranscodedRelease {
+            path: final_output_dir,
+            command: commands[position].clone(),
+            encoder_settings,
+        });
+    }
+
+    Ok(targets
+        .iter()
+        .zip(failures)
+        .zip(successes)
+        .map(|((target, failure), success)| {
+            let result = match (failure, success) {
+                (Some(e), _) => Err(e),
+                (None, Some(success)) => Ok(success),
+                (None, None) => unreachable!("every target either failed or succeeded"),
+            };
+
+            (target.format, result)
+        })
+        .collect())
+}
+
+/// A format that failed is transcoded from scratch by the next run, unless it is resumed, then the
+/// tracks which did succeed are kept for it.
+async fn remove_failed_staging(
+    staging_dir: &Path,
+    options: &TranscodeOptions,
+) -> anyhow::Result<()> {
+    if !options.resume {
+        fs::remove_dir_all(staging_dir).await?;
+    }
+
+    Ok(())
 }
 
+/// Checks the LAME header of every track of an MP3 format, the settings of the first track are
+/// returned so they can be mentioned in the description.
+async fn verify_lame_headers(
+    tracks: &[(PathBuf, PathBuf)],
+    format: ReleaseType,
+    sample_rate_plan: &SampleRatePlan,
+) -> anyhow::Result<Option<String>> {
+    let mut encoder_settings = None;
+
+    for (source, output) in tracks {
+        let header = lame::verify_lame_header(source, output, format, sample_rate_plan).await?;
+
+        if encoder_settings.is_none() {
+            encoder_settings = Some(header.to_string());
+        }
+    }
+
+    Ok(encoder_settings)
+}
+
+/// Decodes (and resamples if needed) the given FLAC once per bit depth and sample rate and feeds
+/// the decoded audio to the encoders of every requested output format of those at the same time. A
+/// failed decode fails the whole track, a failed encoder only fails its own format. When
+/// resuming, outputs that are already complete are kept and only the missing or broken ones are
+/// encoded. Multichannel sources are only accepted with a downmix to stereo, tracks not at the
+/// rate planned for them are resampled to it and emphasized tracks are de-emphasized.
 pub async fn transcode(
+    flac_dir: &PathBuf,
     flac_file_path: &PathBuf,
-    output_dir: &PathBuf,
-    format: ReleaseType,
-) -> anyhow::Result<(PathBuf, String)> {
+    outputs: &[(ReleaseType, PathBuf)],
+    options: &TranscodeOptions,
+) -> anyhow::Result<Vec<TrackOutput>> {
+    let backend = options.backend.as_ref();
+    let deemphasis = options.emphasized_tracks.contains(flac_file_path);
+
     let flac_file_cloned = flac_file_path.clone();
     let reader = tokio::task::spawn_blocking(move || FlacReader::open(flac_file_cloned)).await??;
 
     let info = reader.streaminfo();
 
-    let sample_rate = info.sample_rate;
-    let bits_per_sample = info.bits_per_sample;
-    let resample = sample_rate > 48000 || bits_per_sample > 16;
+    let source = SourceInfo {
+        sample_rate: info.sample_rate,
+        bits_per_sample: info.bits_per_sample,
+        channels: info.channels,
+    };
 
-    let needed_sample_rate = if resample {
-        if sample_rate % 44100 == 0 {
-            Some(44100)
-        } else if sample_rate % 48000 == 0 {
-            Some(48000)
-        } else {
-            return Err(TranscodeError::UnknownSampleRateError(
-                flac_file_path.clone(),
-                sample_rate,
-            )
-            .into());
-        }
-    } else {
-        None
+    let downmix = match info.channels {
+        1 | 2 => None,
+        channels => match Downmix::new(channels) {
+            Some(downmix_to_stereo) if options.downmix => Some(downmix_to_stereo),
+            _ => return Err(TranscodeError::TranscodeDownmixError(flac_file_path.clone()).into()),
+        },
     };
 
-    if info.channels > 2 {
-        return Err(TranscodeError::TranscodeDownmixError(flac_file_path.clone()).into());
+    let native_decoder = !options.external_decoder && backend.accepts_native_decoder();
+
+    if deemphasis && !native_decoder && !backend.supports_deemphasis() {
+        return Err(TranscodeError::DeemphasisUnsupported(flac_file_path.clone()).into());
+    }
+
+    // Keep the relative layout of the source (e.g. CD1/CD2 subfolders) so tracks of different
+    // discs sharing the same file name do not overwrite each other
+    let relative_path = flac_file_path.strip_prefix(flac_dir)?;
+
+    let mut track_outputs: Vec<Option<TrackOutput>> = outputs.iter().map(|_| None).collect();
+
+    let decodes = outputs
+        .iter()
+        .map(|(format, _)| output_decode(*format, &options.sample_rate_plan))
+        .collect::<BTreeSet<(u32, u32)>>();
+
+    for (target_bits_per_sample, target_sample_rate) in decodes {
+        let group = outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, (format, _))| {
+                output_decode(*format, &options.sample_rate_plan)
+                    == (target_bits_per_sample, target_sample_rate)
+            })
+            .collect::<Vec<(usize, &(ReleaseType, PathBuf))>>();
+
+        let settings = DecodeSettings {
+            target_sample_rate: needed_sample_rate(
+                &source,
+                target_sample_rate,
+                target_bits_per_sample,
+            ),
+            target_bits_per_sample,
+            downmix: downmix.clone(),
+            deemphasis,
+            padded_source: options.padded_source,
+        };
+
+        let (decoder, flac_decoder_command_str, pcm_format) =
+            select_decoder(flac_file_path, &source, settings, native_decoder, backend);
+
+        let mut encoder_commands = Vec::new();
+        let mut encoded_indices = Vec::new();
+
+        for (index, (format, output_dir)) in group {
+            let file_extension_to_use = match format {
+                Mp3V0 => ".mp3",
+                Mp3320 => ".mp3",
+                Mp3V2 => ".mp3",
+                Flac => ".flac",
+                Flac24 => ".flac",
+                Aac256 => ".m4a",
+                Opus => ".opus",
+            };
+
+            let output_file_path = output_dir.join(relative_path.parent().unwrap()).join(
+                flac_file_path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .replace(".flac", file_extension_to_use),
+            );
+
+            fs::create_dir_all(output_file_path.parent().unwrap()).await?;
+
+            let (cmd, encoder_command_str) =
+                backend.encoder(*format, &output_file_path, pcm_format.as_ref());
+
+            let reused =
+                options.resume && is_complete_output(flac_file_path, &output_file_path).await;
+
+            if !reused {
+                encoder_commands.push(("encode", cmd));
+                encoded_indices.push(index);
+            }
+
+            track_outputs[index] = Some(TrackOutput {
+                path: output_file_path.clone(),
+                command: backend.render_process(&flac_decoder_command_str, &encoder_command_str),
+                result: Ok(()),
+                reused,
+            });
+        }
+
+        if encoder_commands.is_empty() {
+            continue;
+        }
+
+        let encoder_results = run_fan_out(decoder, encoder_commands).await?;
+
+        for (index, result) in encoded_indices.into_iter().zip(encoder_results) {
+            if let Some(output) = track_outputs[index].as_mut() {
+                output.result = result;
+            }
+        }
+    }
+
+    Ok(track_outputs.into_iter().flatten().collect())
+}
+
+/// Downsampled 24 bit FLACs keep their bit depth, every other format is encoded from 16 bit.
+fn output_bits_per_sample(format: ReleaseType) -> u32 {
+    match format {
+        Flac24 => 24,
+        _ => 16,
     }
+}
 
-    let file_extension_to_use = match format {
-        Mp3V0 => ".mp3",
-        Mp3320 => ".mp3",
-        Flac => ".flac",
-        Flac24 => ".flac",
+/// The bit depth and sample rate the given format is encoded from, outputs sharing both share
+/// one decode.
+fn output_decode(format: ReleaseType, sample_rate_plan: &SampleRatePlan) -> (u32, u32) {
+    let sample_rate = match format {
+        Mp3V0 | Mp3320 | Mp3V2 => sample_rate_plan.mp3_target_sample_rate(),
+        _ => sample_rate_plan.target_sample_rate,
     };
 
-    let output_file_path = output_dir.join(
-        flac_file_path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .replace(".flac", file_extension_to_use),
-    );
-
-    let flac_file_path_str = flac_file_path.to_str().unwrap();
-    let output_file_path_str = output_file_path.to_str().unwrap();
-
-    let mut flac_decoder_command;
-    let mut transcoding_commands_str = Vec::new();
-
-    if resample {
-        flac_decoder_command = Command::new(get_sox_executable());
-        flac_decoder_command.args(&[
-            flac_file_path_str,
-            "-G",
-            "-b",
-            "16",
-            "-t",
-            "wav",
-            "-",
-            "rate",
-            "-v",
-            "-L",
-            needed_sample_rate.unwrap().to_string().as_str(),
-            "dither",
-        ]);
-        transcoding_commands_str.push(format!(
-            "sox input.flac -G -b 16 -t wav - rate -v -L {} dither",
-            needed_sample_rate.unwrap().to_string().as_str()
-        ));
-    } else {
-        flac_decoder_command = Command::new(get_flac_executable());
-        flac_decoder_command.args(&["-dcs", "--", flac_file_path_str]);
-        transcoding_commands_str.push("flac -dcs -- input.flac".to_string());
+    (output_bits_per_sample(format), sample_rate)
+}
+
+/// Tracks at another rate than the target or with more bits than the target are resampled, the
+/// latter only to reduce the bit depth with dither.
+fn needed_sample_rate(
+    source: &SourceInfo,
+    target_sample_rate: u32,
+    target_bits_per_sample: u32,
+) -> Option<u32> {
+    if source.sample_rate == target_sample_rate && source.bits_per_sample <= target_bits_per_sample
+    {
+        return None;
     }
 
-    let mut transcoding_steps = Vec::new();
-    transcoding_steps.push(flac_decoder_command);
-
-    if format == Mp3V0 {
-        let mut cmd = Command::new(get_lame_executable());
-        cmd.args(&[
-            "-S",
-            "-V",
-            "0",
-            "--vbr-new",
-            "--ignore-tag-errors",
-            "-",
-            output_file_path_str,
-        ]);
-        transcoding_steps.push(cmd);
-        transcoding_commands_str
-            .push("lame -S -V 0 --vbr-new --ignore-tag-errors - output.mp3".to_string());
-    } else if format == Mp3320 {
-        let mut cmd = Command::new(get_lame_executable());
-        cmd.args(&[
-            "-S",
-            "-h",
-            "-b",
-            "320",
-            "--ignore-tag-errors",
-            "-",
-            output_file_path_str,
-        ]);
-        transcoding_steps.push(cmd);
-        transcoding_commands_str
-            .push("lame -S -h -b 320 --ignore-tag-errors - output.mp3".to_string());
-    } else if format == Flac {
-        let mut cmd = Command::new(get_flac_executable());
-        cmd.args(&["--best", "-o", output_file_path_str, "-"]);
-        transcoding_steps.push(cmd);
-        transcoding_commands_str.push("flac --best -o output.flac -".to_string());
+    Some(target_sample_rate)
+}
+
+/// Picks the built-in decoder or the one of the backend, together with its rendering for the
+/// description and the layout of the PCM it produces.
+fn select_decoder(
+    flac_file_path: &Path,
+    source: &SourceInfo,
+    settings: DecodeSettings,
+    native_decoder: bool,
+    backend: &dyn EncoderBackend,
+) -> (Decoder, String, Option<PcmFormat>) {
+    if native_decoder {
+        let command_str =
+            native::describe_native_pipeline(source.sample_rate, source.bits_per_sample, &settings);
+        let pcm_format = PcmFormat {
+            sample_rate: settings.target_sample_rate.unwrap_or(source.sample_rate),
+            channels: if settings.downmix.is_some() {
+                2
+            } else {
+                source.channels
+            },
+            bits_per_sample: settings.target_bits_per_sample,
+        };
+
+        return (
+            Decoder::Native {
+                path: flac_file_path.to_path_buf(),
+                settings,
+            },
+            command_str,
+            Some(pcm_format),
+        );
     }
 
-    let mut commands = Vec::new();
-
-    if format == Flac && resample {
-        let mut cmd = Command::new(get_sox_executable());
-        cmd.args(&[
-            flac_file_path_str,
-            "-G",
-            "-b",
-            "16",
-            output_file_path_str,
-            "rate",
-            "-v",
-            "-L",
-            needed_sample_rate.unwrap().to_string().as_str(),
-            "dither",
-        ]);
-        commands.push(cmd);
-        transcoding_commands_str.clear();
-        transcoding_commands_str.push(format!(
-            "sox input.flac -G -b 16 output.flac rate -v -L {} dither",
-            needed_sample_rate.unwrap().to_string().as_str()
-        ));
-    } else {
-        for step in transcoding_steps {
-            commands.push(step);
-        }
+    let external = backend.decoder(flac_file_path, source, &settings);
+
+    (
+        Decoder::External(external.step, external.command),
+        external.command_str,
+        external.pcm_format,
+    )
+}
+
+/// An already existing output counts as complete when it decodes to the length of its source and
+/// carries the basic tags, anything else gets encoded again.
+async fn is_complete_output(flac_file_path: &Path, output_file_path: &Path) -> bool {
+    if !fs::try_exists(output_file_path).await.unwrap_or(false) {
+        return false;
     }
 
-    run_commands(commands).await?;
+    if verify::verify_output(flac_file_path, output_file_path)
+        .await
+        .is_err()
+    {
+        return false;
+    }
 
-    Ok((output_file_path, transcoding_commands_str.join(" | ")))
+    crate::tags::util::has_basic_tags(output_file_path)
 }
 
-async fn run_commands(commands: Vec<Command>) -> anyhow::Result<()> {
-    let mut content = vec![];
-
-    for mut command in commands {
-        command.stdin(Stdio::piped());
-        command.stdout(Stdio::piped());
-        command.stderr(Stdio::piped());
-        let mut child = command.spawn()?;
-        if content.len() > 0 {
-            let mut stdin = child.stdin.take().unwrap();
-            tokio::spawn(async move {
-                stdin.write_all(&content).await.unwrap();
-            });
+struct SpawnedStep {
+    step: &'static str,
+    command: String,
+    child: Child,
+    stderr_handle: JoinHandle<std::io::Result<Vec<u8>>>,
+}
+
+fn spawn_step(
+    step: &'static str,
+    mut command: Command,
+    stdin: Stdio,
+    stdout: Stdio,
+) -> anyhow::Result<SpawnedStep> {
+    command.stdin(stdin);
+    command.stdout(stdout);
+    command.stderr(Stdio::piped());
+    command.kill_on_drop(true);
+
+    let rendered_command = render_command(&command);
+
+    let mut child = command.spawn()?;
+
+    // stderr has to be drained while the pipeline runs, otherwise a chatty step could
+    // fill up the pipe buffer and stall the whole pipeline
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_handle = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).await?;
+        Ok::<Vec<u8>, std::io::Error>(buffer)
+    });
+
+    Ok(SpawnedStep {
+        step,
+        command: rendered_command,
+        child,
+        stderr_handle,
+    })
+}
+
+async fn wait_step(mut spawned: SpawnedStep) -> anyhow::Result<Option<TranscodeError>> {
+    let status = spawned.child.wait().await?;
+    let stderr = spawned.stderr_handle.await??;
+
+    if status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(EncoderFailed {
+        step: spawned.step.to_string(),
+        command: spawned.command,
+        exit_code: status.code(),
+        stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+    }))
+}
+
+/// Streams the output of the decoder into the stdin of every encoder. Each chunk is written to
+/// all encoders before the next one is read, so the slowest encoder applies backpressure to the
+/// decoder and never more than one chunk is held in memory.
+async fn run_fan_out(
+    decoder: Decoder,
+    encoders: Vec<(&'static str, Command)>,
+) -> anyhow::Result<Vec<anyhow::Result<()>>> {
+    let mut external_decoder = None;
+    let mut native_decoder = None;
+
+    match decoder {
+        Decoder::External(step, command) => {
+            let mut spawned = spawn_step(step, command, Stdio::null(), Stdio::piped())?;
+            let stdout = spawned.child.stdout.take().unwrap();
+            external_decoder = Some((spawned, stdout));
+        }
+        Decoder::Native { path, settings } => {
+            native_decoder = Some(native::spawn_native_decoder(path, settings))
         }
-        let output = child.wait_with_output().await?;
-        content = output.stdout;
     }
 
-    Ok(())
+    let mut spawned_encoders = Vec::with_capacity(encoders.len());
+    let mut encoder_stdins: Vec<Option<ChildStdin>> = Vec::with_capacity(encoders.len());
+
+    for (step, command) in encoders {
+        let mut spawned = spawn_step(step, command, Stdio::piped(), Stdio::null())?;
+        encoder_stdins.push(spawned.child.stdin.take());
+        spawned_encoders.push(spawned);
+    }
+
+    let mut buffer = vec![0u8; FAN_OUT_BUFFER_SIZE];
+    let mut native_chunk;
+    let mut all_encoders_died = false;
+
+    loop {
+        let chunk: &[u8] = match (&mut external_decoder, &mut native_decoder) {
+            (Some((_, stdout)), _) => {
+                let read = stdout.read(&mut buffer).await?;
+
+                if read == 0 {
+                    break;
+                }
+
+                &buffer[..read]
+            }
+            (None, Some(native)) => match native.receiver.recv().await {
+                Some(received) => {
+                    native_chunk = received;
+                    &native_chunk
+                }
+                None => break,
+            },
+            (None, None) => unreachable!("the fan-out always has a decoder"),
+        };
+
+        let writes = join_all(encoder_stdins.iter_mut().map(|stdin| async move {
+            match stdin {
+                Some(s) => s.write_all(chunk).await.is_ok(),
+                None => true,
+            }
+        }))
+        .await;
+
+        // An encoder that stopped accepting input has died, its exit status is reported below
+        // while the remaining encoders keep being fed until the decoder is done
+        for (stdin, ok) in encoder_stdins.iter_mut().zip(writes) {
+            if !ok {
+                *stdin = None;
+            }
+        }
+
+        if encoder_stdins.iter().all(|stdin| stdin.is_none()) {
+            all_encoders_died = true;
+            break;
+        }
+    }
+
+    // Closing stdin signals EOF to the encoders so they can finish their files
+    drop(encoder_stdins);
+
+    if let Some((mut decoder, decoder_stdout)) = external_decoder {
+        drop(decoder_stdout);
+
+        if all_encoders_died {
+            // Nobody is left to consume the decoded audio, the decoder failing with a broken pipe
+            // afterwards is only a consequence of that
+            decoder.child.start_kill()?;
+            wait_step(decoder).await?;
+        } else if let Some(failure) = wait_step(decoder).await? {
+            // A failed decoder leaves every encoder with a truncated file, so it fails the whole
+            // track
+            return Err(failure.into());
+        }
+    }
+
+    if let Some(NativeDecoder { handle, receiver }) = native_decoder {
+        // Dropping the receiver stops the native decoder if it is still running
+        drop(receiver);
+
+        let decoded = handle.await?;
+
+        if !all_encoders_died {
+            decoded?;
+        }
+    }
+
+    let mut results = Vec::with_capacity(spawned_encoders.len());
+
+    for spawned in spawned_encoders {
+        results.push(match wait_step(spawned).await? {
+            None => Ok(()),
+            Some(failure) => Err(failure.into()),
+        });
+    }
+
+    Ok(results)
+}
+
+fn render_command(command: &Command) -> String {
+    let command = command.as_std();
+
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|part| {
+            let part = part.to_string_lossy();
+
+            if part.contains(char::is_whitespace) {
+                format!("\"{}\"", part)
+            } else {
+                part.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
 }
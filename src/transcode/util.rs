@@ -1,9 +1,14 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use async_recursion::async_recursion;
 use claxon::FlacReader;
 use tokio::fs;
 
+pub struct DecodedLength {
+    pub samples: u64,
+    pub sample_rate: u32,
+}
+
 #[async_recursion]
 pub async fn copy_other_allowed_files(
     dir_path: &PathBuf,
@@ -93,3 +98,22 @@ pub async fn is_multichannel(flac_dir_path: &PathBuf) -> anyhow::Result<bool> {
 
     return Ok(false);
 }
+
+pub fn decode_flac_length(path: &Path) -> anyhow::Result<DecodedLength> {
+    let mut reader = FlacReader::open(path)?;
+    let sample_rate = reader.streaminfo().sample_rate;
+
+    let mut samples = 0;
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+
+    while let Some(block) = blocks.read_next_or_eof(buffer)? {
+        samples += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+
+    Ok(DecodedLength {
+        samples,
+        sample_rate,
+    })
+}
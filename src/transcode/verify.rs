@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use claxon::FlacReader;
+use futures::future::join_all;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::sync::Semaphore;
+
+use crate::tags::opus::read_opus_length;
+use crate::transcode::error::TranscodeError::{OutputLengthMismatch, VerificationFailed};
+use crate::transcode::util::{decode_flac_length, DecodedLength};
+
+/// LAME adds its encoder delay and pads the last frame, so a decoded MP3 is always a bit longer
+/// than its source. Four MPEG-1 Layer III frames cover the delay plus the padding comfortably.
+const MP3_PADDING_TOLERANCE: u64 = 4 * 1152;
+
+/// AAC encoders prepend their priming samples and pad the last frame of 1024 samples, which
+/// decoders without gapless support keep.
+const AAC_PADDING_TOLERANCE: u64 = 3 * 1024;
+
+/// Opus always runs at 48kHz, the resampler of the encoder may be off by a part of a frame.
+const OPUS_TOLERANCE: u64 = 960;
+
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// Resamplers may round the length of their output differently by a sample or two.
+const RESAMPLE_TOLERANCE: u64 = 2;
+
+/// Decodes the whole file and counts the samples (per channel) in it, which also proves that
+/// the file decodes cleanly from start to end.
+pub async fn decoded_length(path: &Path) -> anyhow::Result<DecodedLength> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let extension = path
+            .extension()
+            .map(|e| e.to_str().unwrap().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "flac" => decode_flac_length(&path),
+            "mp3" | "m4a" => decode_symphonia_length(&path, &extension),
+            "opus" => Ok(DecodedLength {
+                samples: read_opus_length(&path)?,
+                sample_rate: OPUS_SAMPLE_RATE,
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Can not decode \"{}\", unsupported file type",
+                path.display()
+            )),
+        }
+    })
+    .await?
+}
+
+/// Checks that the transcoded output decodes completely and has the length of its source.
+pub async fn verify_output(source: &Path, output: &Path) -> anyhow::Result<()> {
+    let source_cloned = source.to_path_buf();
+    let streaminfo = tokio::task::spawn_blocking(move || FlacReader::open(source_cloned))
+        .await??
+        .streaminfo();
+
+    let source_samples = match streaminfo.samples {
+        Some(samples) => samples,
+        None => decoded_length(source).await?.samples,
+    };
+
+    let decoded = decoded_length(output).await?;
+
+    let expected = (source_samples as u128 * decoded.sample_rate as u128
+        / streaminfo.sample_rate as u128) as u64;
+
+    let extension = output
+        .extension()
+        .map(|e| e.to_str().unwrap().to_lowercase())
+        .unwrap_or_default();
+
+    let (lower, upper) = if extension == "mp3" {
+        (
+            expected.saturating_sub(RESAMPLE_TOLERANCE),
+            expected + MP3_PADDING_TOLERANCE,
+        )
+    } else if extension == "m4a" {
+        (
+            expected.saturating_sub(RESAMPLE_TOLERANCE),
+            expected + AAC_PADDING_TOLERANCE,
+        )
+    } else if extension == "opus" {
+        (
+            expected.saturating_sub(OPUS_TOLERANCE),
+            expected + OPUS_TOLERANCE,
+        )
+    } else if decoded.sample_rate != streaminfo.sample_rate {
+        (
+            expected.saturating_sub(RESAMPLE_TOLERANCE),
+            expected + RESAMPLE_TOLERANCE,
+        )
+    } else {
+        (expected, expected)
+    };
+
+    if decoded.samples < lower || decoded.samples > upper {
+        return Err(OutputLengthMismatch(output.to_path_buf(), expected, decoded.samples).into());
+    }
+
+    Ok(())
+}
+
+/// Verifies every transcoded track of a format against its source, the first track that does not
+/// verify is named in the returned error.
+pub async fn verify_tracks(
+    tracks: &[(PathBuf, PathBuf)],
+    semaphore: Arc<Semaphore>,
+) -> anyhow::Result<()> {
+    let results = join_all(tracks.iter().map(|(source, output)| {
+        let semaphore = semaphore.clone();
+
+        async move {
+            let _permit = semaphore.acquire().await?;
+            verify_output(source, output).await
+        }
+    }))
+    .await;
+
+    for ((_, output), result) in tracks.iter().zip(results) {
+        if let Err(e) = result {
+            return Err(VerificationFailed(output.clone(), e.to_string()).into());
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_symphonia_length(path: &Path, extension: &str) -> anyhow::Result<DecodedLength> {
+    let file = std::fs::File::open(path)?;
+    let media_source = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        media_source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let mut format = probed.format;
+
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow::anyhow!("No audio track found in \"{}\"", path.display()))?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = 0;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        samples += decoder.decode(&packet)?.frames() as u64;
+    }
+
+    Ok(DecodedLength {
+        samples,
+        sample_rate,
+    })
+}